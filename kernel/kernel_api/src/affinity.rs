@@ -0,0 +1,22 @@
+//! Chooses which harts processes can run on
+
+use kernel_syscall_abi::{AffinityError, SyscallNumbers};
+
+use crate::{syscall::do_syscall_2, syscall_return::AsResult, Handle};
+
+fn set_affinity_raw(fd: usize, allowed_harts: usize) -> Result<usize, AffinityError> {
+    unsafe { do_syscall_2(SyscallNumbers::SetAffinity as usize, fd, allowed_harts) }
+        .as_generic_result()
+        .map_err(|e| e.as_result())
+}
+
+/// Makes this process only run on the harts in `allowed_harts` (bit N = hart ID N, ALL_HARTS for any).
+/// Returns the previous mask
+pub fn set_affinity(allowed_harts: usize) -> Result<usize, AffinityError> {
+    set_affinity_raw(usize::MAX, allowed_harts)
+}
+
+/// Like `set_affinity`, but for the process that `process` refers to (for example, a hatched process egg)
+pub fn set_affinity_of(process: &Handle, allowed_harts: usize) -> Result<usize, AffinityError> {
+    set_affinity_raw(process.fd(), allowed_harts)
+}
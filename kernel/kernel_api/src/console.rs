@@ -0,0 +1,12 @@
+//! Controls how the console handles input. Console handles are usually the standard streams
+
+use kernel_syscall_abi::console::{ConsoleError, ConsoleMode, ConsoleRequest};
+
+use crate::{syscall_return::AsResult, Handle};
+
+pub fn set_mode(console: &Handle, mode: ConsoleMode) -> Result<(), ConsoleError> {
+    console
+        .write(&[], &[ConsoleRequest::SetMode as usize, mode as usize])
+        .map(|_| ())
+        .map_err(|e| e.as_result())
+}
@@ -1,7 +1,7 @@
 use kernel_syscall_abi::filesystem::FilesystemError;
 
 use crate::{
-    syscall::{do_syscall_1, do_syscall_slice},
+    syscall::{do_syscall_1, do_syscall_3, do_syscall_slice},
     syscall_return::{AsResult, SyscallErrorData},
 };
 
@@ -11,6 +11,13 @@ pub struct Handle(usize);
 pub type Result<T> = core::result::Result<T, (usize, SyscallErrorData)>;
 
 impl Handle {
+    // The caller must make sure that only one Handle closes the fd
+    pub(crate) const fn from_fd(fd: usize) -> Self {
+        Self(fd)
+    }
+    pub(crate) fn fd(&self) -> usize {
+        self.0
+    }
     pub fn open(backend: usize, options: &[usize]) -> Result<Self> {
         let mut params = [0; 7];
         params[0..1].copy_from_slice(&[backend]);
@@ -48,6 +55,37 @@ impl Handle {
             .as_generic_result()?,
         )
     }
+    /// Creates a new handle that shares the state of this one (for example, the position in a file)
+    pub fn duplicate(&self) -> Result<Self> {
+        self.duplicate_to(usize::MAX)
+    }
+    /// Like `duplicate`, but the new handle gets the fd number `fd`
+    pub fn duplicate_to(&self, fd: usize) -> Result<Self> {
+        Ok(Self(
+            unsafe {
+                do_syscall_3(
+                    kernel_syscall_abi::SyscallNumbers::Duplicate as usize,
+                    self.0,
+                    usize::MAX,
+                    fd,
+                )
+            }
+            .as_generic_result()?,
+        ))
+    }
+    /// Gives a duplicate of this handle to `destination` (for example, a process egg), with the fd number `fd`.
+    /// If `fd` is usize::MAX, any free fd number is used. Returns the fd number
+    pub fn send_to(&self, destination: &Handle, fd: usize) -> Result<usize> {
+        unsafe {
+            do_syscall_3(
+                kernel_syscall_abi::SyscallNumbers::Duplicate as usize,
+                self.0,
+                destination.0,
+                fd,
+            )
+        }
+        .as_generic_result()
+    }
     // private: should only be called once
     fn close(&self) -> Result<()> {
         unsafe {
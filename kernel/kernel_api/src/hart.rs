@@ -0,0 +1,29 @@
+//! Takes harts offline and brings them back online
+
+use kernel_syscall_abi::{HartError, SyscallNumbers};
+
+use crate::{syscall::do_syscall_2, syscall_return::AsResult};
+
+fn set_online(hart_id: usize, online: bool) -> Result<(), HartError> {
+    unsafe {
+        do_syscall_2(
+            SyscallNumbers::SetHartOnline as usize,
+            hart_id,
+            online as usize,
+        )
+    }
+    .as_generic_result()
+    .map(|_| ())
+    .map_err(|e| e.as_result())
+}
+
+/// Stops a hart after moving its processes to the other harts. Only root can do this.
+/// The hart might still be running for a moment after this returns
+pub fn take_offline(hart_id: usize) -> Result<(), HartError> {
+    set_online(hart_id, false)
+}
+
+/// Starts a hart that was taken offline. Only root can do this
+pub fn bring_online(hart_id: usize) -> Result<(), HartError> {
+    set_online(hart_id, true)
+}
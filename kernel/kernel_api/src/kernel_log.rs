@@ -0,0 +1,62 @@
+//! Reads the kernel log buffer and changes kernel log levels
+
+use alloc::{string::String, vec::Vec};
+
+use flat_bytes::Flat;
+use kernel_syscall_abi::kernel_log::{KernelLogError, KernelLogOpenMode, KernelLogRecordHeader};
+
+use crate::{syscall_return::AsResult, Handle};
+
+pub type Result<T> = core::result::Result<T, KernelLogError>;
+
+pub struct KernelLogRecord {
+    pub header: KernelLogRecordHeader,
+    pub text: String,
+}
+
+pub struct KernelLog {
+    handle: Handle,
+    buffer: Vec<u8>,
+}
+
+impl KernelLog {
+    /// If `follow` is true, `read` blocks until there are new records instead of returning nothing
+    pub fn open(follow: bool) -> Result<Self> {
+        let mode = if follow {
+            KernelLogOpenMode::Follow
+        } else {
+            KernelLogOpenMode::Snapshot
+        };
+        let mut buffer = Vec::new();
+        buffer.resize(4096, 0);
+        Ok(Self {
+            handle: Handle::open(7, &[mode as usize]).map_err(|e| e.as_result())?,
+            buffer,
+        })
+    }
+
+    /// Returns the records that haven't been read yet. Returns an empty Vec when there are none
+    pub fn read(&mut self) -> Result<Vec<KernelLogRecord>> {
+        let read = self
+            .handle
+            .read(&mut self.buffer, &[])
+            .map_err(|e| e.as_result())?;
+        let mut records = Vec::new();
+        let mut data = &self.buffer[..read];
+        while let Some((header, size)) = KernelLogRecordHeader::deserialize_with_size(data) {
+            let text = String::from_utf8_lossy(&data[size..size + header.text_length]).into_owned();
+            data = &data[size + header.text_length..];
+            records.push(KernelLogRecord { header, text });
+        }
+        Ok(records)
+    }
+
+    /// Sets the level (0 = Off to 5 = Trace) for kernel modules whose path starts with `module`.
+    /// An empty `module` sets the default level
+    pub fn set_level(&self, module: &str, level: usize) -> Result<()> {
+        self.handle
+            .write(module.as_bytes(), &[level])
+            .map(|_| ())
+            .map_err(|e| e.as_result())
+    }
+}
@@ -11,19 +11,33 @@
 #![register_tool(rust_analyzer)]
 extern crate alloc;
 
+pub mod affinity;
 pub mod allocator;
+pub mod console;
 pub mod elf;
 pub mod handle;
+pub mod hart;
 pub mod interrupt;
+pub mod kernel_log;
 pub mod memory;
 pub mod panic;
 pub mod println;
+pub mod priority;
 pub mod process_egg;
+pub mod process_info;
+pub mod signal;
+pub mod stdio;
 pub mod syscall;
 pub mod syscall_return;
+pub mod time;
+pub mod user_server;
 
 pub fn exit() -> ! {
-    unsafe { syscall::do_syscall_0(SyscallNumbers::Exit as usize) };
+    exit_with_code(0)
+}
+
+pub fn exit_with_code(code: usize) -> ! {
+    unsafe { syscall::do_syscall_1(SyscallNumbers::Exit as usize, code) };
     // Otherwise, loop forever
     loop {}
 }
@@ -1,6 +1,9 @@
 use kernel_syscall_abi::{AllocPagesError, SyscallNumbers};
 
-use crate::{syscall::do_syscall_4, syscall_return::AsResult};
+use crate::{
+    syscall::{do_syscall_2, do_syscall_4},
+    syscall_return::AsResult,
+};
 
 pub type Result<T> = core::result::Result<T, AllocPagesError>;
 
@@ -32,3 +35,11 @@ pub fn alloc_pages(
     };
     v.as_generic_result_nonnull().map_err(|s| s.as_result())
 }
+
+/// Unmaps the pages from `virtual_addr` to `virtual_addr + size`
+pub fn free_pages(virtual_addr: usize, size: usize) -> Result<()> {
+    unsafe { do_syscall_2(SyscallNumbers::FreePages as usize, virtual_addr, size) }
+        .as_generic_result()
+        .map(|_| ())
+        .map_err(|s| s.as_result())
+}
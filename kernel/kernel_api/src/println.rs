@@ -3,7 +3,7 @@ macro_rules! print
 {
 	($($args:tt)+) => (#[allow(unused_unsafe)] {
 			use core::fmt::Write;
-			let mut log_output = ::kernel_api::Handle::open(1, &[]).unwrap();
+			let mut log_output = ::kernel_api::stdio::stdout();
 			let _ = write!(log_output, $($args)+);
 			});
 }
@@ -26,7 +26,7 @@ macro_rules! print_crate
 {
 	($($args:tt)+) => (#[allow(unused_unsafe)] {
 			use core::fmt::Write;
-			let mut log_output = crate::Handle::open(1, &[]).unwrap();
+			let mut log_output = crate::stdio::stdout();
 			let _ = write!(log_output, $($args)+);
 			});
 }
@@ -0,0 +1,23 @@
+//! Changes how much CPU time processes get
+
+use kernel_syscall_abi::{PriorityError, SyscallNumbers};
+
+use crate::{syscall::do_syscall_2, syscall_return::AsResult, Handle};
+
+fn set_nice_raw(fd: usize, nice: isize) -> Result<isize, PriorityError> {
+    unsafe { do_syscall_2(SyscallNumbers::SetPriority as usize, fd, nice as usize) }
+        .as_generic_result()
+        .map(|old| old as isize)
+        .map_err(|e| e.as_result())
+}
+
+/// Sets the nice level of this process, from MIN_NICE (most CPU time) to MAX_NICE.
+/// Returns the previous one. Only user ID 0 can lower it
+pub fn set_nice(nice: isize) -> Result<isize, PriorityError> {
+    set_nice_raw(usize::MAX, nice)
+}
+
+/// Like `set_nice`, but for the process that `process` refers to (for example, a hatched process egg)
+pub fn set_nice_of(process: &Handle, nice: isize) -> Result<isize, PriorityError> {
+    set_nice_raw(process.fd(), nice)
+}
@@ -1,7 +1,11 @@
 use flat_bytes::Flat;
-use kernel_syscall_abi::process_egg::{ProcessEggError, ProcessEggPacketHeader};
+use kernel_syscall_abi::{
+    process_egg::{ProcessEggError, ProcessEggPacketHeader},
+    signal::{Signal, SignalError},
+    AffinityError, PriorityError,
+};
 
-use crate::Handle;
+use crate::{syscall_return::AsResult, Handle};
 
 pub struct ProcessEgg {
     handle: Handle,
@@ -30,8 +34,34 @@ impl ProcessEgg {
     pub fn get_start_address(&mut self) -> usize {
         todo!()
     }
-    pub fn hatch(&mut self) {
+    /// Makes the process start with a duplicate of `handle` as the fd `fd`
+    pub fn set_handle(&mut self, handle: &Handle, fd: usize) -> crate::handle::Result<usize> {
+        handle.send_to(&self.handle, fd)
+    }
+    /// Starts the process and returns its PID
+    pub fn hatch(&mut self) -> crate::handle::Result<usize> {
         let mut packet = ProcessEggPacketHeader::Hatch.serialize();
-        self.handle.write(&mut packet, &[]);
+        self.handle.write(&mut packet, &[])
+    }
+    /// Blocks until the hatched process exits and returns its exit code
+    /// (ABNORMAL_TERMINATION if it didn't exit by itself)
+    pub fn wait(&self) -> Result<usize, ProcessEggError> {
+        self.handle.read(&mut [], &[]).map_err(|e| e.as_result())
+    }
+    /// Sends a signal to the hatched process
+    pub fn signal(&self, signal: Signal) -> Result<(), SignalError> {
+        crate::signal::send(&self.handle, signal)
+    }
+    /// Sets the nice level of the hatched process. See `priority::set_nice`
+    pub fn set_nice(&self, nice: isize) -> Result<isize, PriorityError> {
+        crate::priority::set_nice_of(&self.handle, nice)
+    }
+    /// Sets the harts the hatched process can run on. See `affinity::set_affinity`
+    pub fn set_affinity(&self, allowed_harts: usize) -> Result<usize, AffinityError> {
+        crate::affinity::set_affinity_of(&self.handle, allowed_harts)
+    }
+    /// Terminates the hatched process. Its exit code becomes KILLED_BY_SIGNAL
+    pub fn kill(&self) -> Result<(), SignalError> {
+        self.signal(Signal::Kill)
     }
 }
@@ -0,0 +1,126 @@
+//! Lists processes and what they are doing, and how busy each hart is
+
+use alloc::{string::String, vec::Vec};
+
+use flat_bytes::Flat;
+use kernel_syscall_abi::process_info::{
+    HandleInfoHeader, HartInfoHeader, ProcessInfoError, ProcessInfoHeader, ProcessInfoState,
+    ALL_PROCESSES, HARTS,
+};
+
+use crate::{syscall_return::AsResult, Handle};
+
+pub type Result<T> = core::result::Result<T, ProcessInfoError>;
+
+pub struct HandleInfo {
+    pub fd: usize,
+    pub backend_name: String,
+}
+
+pub struct ProcessInfo {
+    pub pid: usize,
+    pub user_id: usize,
+    pub supervisor: bool,
+    pub state: Option<ProcessInfoState>,
+    pub memory_usage: usize,
+    // In timer ticks
+    pub user_time: usize,
+    pub kernel_time: usize,
+    pub nice: isize,
+    pub allowed_harts: usize,
+    pub name: String,
+    pub handles: Vec<HandleInfo>,
+}
+
+fn parse_string(data: &[u8], length: usize) -> (String, &[u8]) {
+    (
+        String::from_utf8_lossy(&data[..length]).into_owned(),
+        &data[length..],
+    )
+}
+
+fn parse_process(data: &[u8]) -> Option<(ProcessInfo, &[u8])> {
+    let (header, size) = ProcessInfoHeader::deserialize_with_size(data)?;
+    let (name, mut data) = parse_string(&data[size..], header.name_length);
+    let mut handles = Vec::new();
+    for _ in 0..header.handle_count {
+        let (handle, size) = HandleInfoHeader::deserialize_with_size(data)?;
+        let (backend_name, rest) = parse_string(&data[size..], handle.backend_name_length);
+        data = rest;
+        handles.push(HandleInfo {
+            fd: handle.fd,
+            backend_name,
+        });
+    }
+    Some((
+        ProcessInfo {
+            pid: header.pid,
+            user_id: header.user_id,
+            supervisor: header.supervisor != 0,
+            state: ProcessInfoState::try_from(header.state).ok(),
+            memory_usage: header.memory_usage,
+            user_time: header.user_time,
+            kernel_time: header.kernel_time,
+            nice: header.nice as isize,
+            allowed_harts: header.allowed_harts,
+            name,
+            handles,
+        },
+        data,
+    ))
+}
+
+/// Reads the whole handle, calling `parse` with each buffer that was read
+fn read_all(option: usize, mut parse: impl FnMut(&[u8])) -> Result<()> {
+    let handle = Handle::open(8, &[option]).map_err(|e| e.as_result())?;
+    let mut buffer = Vec::new();
+    buffer.resize(4096, 0);
+    loop {
+        let read = match handle.read(&mut buffer, &[]).map_err(|e| e.as_result()) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(ProcessInfoError::BufferTooSmall) => {
+                // A process with a lot of handles
+                let new_len = buffer.len() * 2;
+                buffer.resize(new_len, 0);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        parse(&buffer[..read]);
+    }
+}
+
+fn read_processes(pid: usize) -> Result<Vec<ProcessInfo>> {
+    let mut processes = Vec::new();
+    read_all(pid, |mut data| {
+        while let Some((process, rest)) = parse_process(data) {
+            data = rest;
+            processes.push(process);
+        }
+    })?;
+    Ok(processes)
+}
+
+/// Returns every process, ordered by PID
+pub fn list() -> Result<Vec<ProcessInfo>> {
+    read_processes(ALL_PROCESSES)
+}
+
+pub fn get(pid: usize) -> Result<ProcessInfo> {
+    read_processes(pid)?
+        .pop()
+        .ok_or(ProcessInfoError::NoSuchProcess)
+}
+
+/// Returns the idle time of every hart, ordered by hart ID
+pub fn harts() -> Result<Vec<HartInfoHeader>> {
+    let mut harts = Vec::new();
+    read_all(HARTS, |mut data| {
+        while let Some((hart, size)) = HartInfoHeader::deserialize_with_size(data) {
+            data = &data[size..];
+            harts.push(hart);
+        }
+    })?;
+    Ok(harts)
+}
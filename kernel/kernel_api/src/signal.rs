@@ -0,0 +1,66 @@
+//! Sending signals to processes, and handling the signals sent to this process
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use kernel_syscall_abi::{
+    signal::{Signal, SignalError},
+    SyscallNumbers,
+};
+
+use crate::{
+    syscall::{do_syscall_0, do_syscall_1, do_syscall_2},
+    syscall_return::AsResult,
+    Handle,
+};
+
+// The fn(Signal) set with set_handler, or 0
+static HANDLER: AtomicUsize = AtomicUsize::new(0);
+
+// The kernel jumps here with the signal in a0
+extern "C" fn trampoline(signal: usize) -> ! {
+    let handler = HANDLER.load(Ordering::Acquire);
+    if handler != 0 {
+        if let Ok(signal) = Signal::try_from(signal) {
+            let handler: fn(Signal) = unsafe { core::mem::transmute(handler) };
+            handler(signal);
+        }
+    }
+    unsafe { do_syscall_0(SyscallNumbers::SignalReturn as usize) };
+    // SignalReturn doesn't return
+    loop {}
+}
+
+/// Makes `handler` get called when this process receives a signal (except Kill).
+/// The process continues what it was doing when the handler returns.
+/// With None, Terminate and Interrupt terminate the process and other signals are ignored
+pub fn set_handler(handler: Option<fn(Signal)>) {
+    match handler {
+        Some(handler) => {
+            HANDLER.store(handler as usize, Ordering::Release);
+            unsafe {
+                do_syscall_1(
+                    SyscallNumbers::SetSignalHandler as usize,
+                    trampoline as usize,
+                )
+            };
+        }
+        None => {
+            unsafe { do_syscall_1(SyscallNumbers::SetSignalHandler as usize, 0) };
+            HANDLER.store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Sends `signal` to the process that `process` refers to (for example, a hatched process egg)
+pub fn send(process: &Handle, signal: Signal) -> Result<(), SignalError> {
+    unsafe {
+        do_syscall_2(
+            SyscallNumbers::Signal as usize,
+            process.fd(),
+            signal as usize,
+        )
+    }
+    .as_generic_result()
+    .map(|_| ())
+    .map_err(|e| e.as_result())
+}
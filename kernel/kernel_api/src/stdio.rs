@@ -0,0 +1,36 @@
+//! The standard streams that every process starts with
+
+use core::{mem::ManuallyDrop, ops::Deref};
+
+use kernel_syscall_abi::{STDERR_FD, STDIN_FD, STDOUT_FD};
+
+use crate::Handle;
+
+/// One of the process's standard streams. Dropping it doesn't close the stream
+pub struct StandardStream(ManuallyDrop<Handle>);
+
+impl Deref for StandardStream {
+    type Target = Handle;
+
+    fn deref(&self) -> &Handle {
+        &self.0
+    }
+}
+
+impl core::fmt::Write for StandardStream {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0.write_str(s)
+    }
+}
+
+pub fn stdin() -> StandardStream {
+    StandardStream(ManuallyDrop::new(Handle::from_fd(STDIN_FD)))
+}
+
+pub fn stdout() -> StandardStream {
+    StandardStream(ManuallyDrop::new(Handle::from_fd(STDOUT_FD)))
+}
+
+pub fn stderr() -> StandardStream {
+    StandardStream(ManuallyDrop::new(Handle::from_fd(STDERR_FD)))
+}
@@ -0,0 +1,143 @@
+//! Waiting and measuring time
+
+use core::{
+    ops::{Add, Sub},
+    time::Duration,
+};
+
+pub use kernel_syscall_abi::time::ClockError;
+use kernel_syscall_abi::{time::ClockId, SyscallNumbers};
+
+use crate::{syscall::do_syscall_1, syscall_return::AsResult, Handle};
+
+/// A point in time since boot, with nanosecond precision. Like std::time::Instant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(
+            unsafe {
+                do_syscall_1(
+                    SyscallNumbers::ClockGet as usize,
+                    ClockId::Monotonic as usize,
+                )
+            }
+            .as_generic_result()
+            .unwrap() as u64,
+        )
+    }
+
+    /// Zero if `earlier` is after this instant
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant(self.0 - rhs.as_nanos() as u64)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Wall-clock time, with nanosecond precision. Like std::time::SystemTime, but it can be unavailable
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SystemTime(u64);
+
+impl SystemTime {
+    pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+    /// Fails with ClockError::Unavailable if the machine doesn't have an RTC
+    pub fn now() -> Result<Self, ClockError> {
+        unsafe {
+            do_syscall_1(
+                SyscallNumbers::ClockGet as usize,
+                ClockId::Realtime as usize,
+            )
+        }
+        .as_generic_result()
+        .map(|nanos| Self(nanos as u64))
+        .map_err(|e| e.as_result())
+    }
+
+    pub fn since_epoch(&self) -> Duration {
+        Duration::from_nanos(self.0)
+    }
+
+    /// Zero if `earlier` is after this time
+    pub fn duration_since(&self, earlier: SystemTime) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, rhs: Duration) -> SystemTime {
+        SystemTime(self.0 + rhs.as_nanos() as u64)
+    }
+}
+
+/// Blocks this process for (at least) `duration`, without using the hart in the meantime
+pub fn sleep(duration: Duration) {
+    unsafe { do_syscall_1(SyscallNumbers::Sleep as usize, duration.as_nanos() as usize) };
+}
+
+/// A timer that fires once or periodically
+pub struct Timer {
+    handle: Handle,
+}
+
+impl Timer {
+    /// Fires once after `after`
+    pub fn once(after: Duration) -> crate::handle::Result<Self> {
+        Ok(Self {
+            handle: Handle::open(9, &[after.as_nanos() as usize, 0])?,
+        })
+    }
+
+    /// Fires every `period`, starting after one period
+    pub fn periodic(period: Duration) -> crate::handle::Result<Self> {
+        Ok(Self {
+            handle: Handle::open(9, &[period.as_nanos() as usize, period.as_nanos() as usize])?,
+        })
+    }
+
+    /// Blocks until the timer fires and returns how many times it fired since the last call.
+    /// Returns 0 right away if the timer fires once and it already did
+    pub fn wait(&self) -> crate::handle::Result<usize> {
+        self.handle.read(&mut [], &[])
+    }
+
+    /// Makes the timer fire after `after`, and then every `period` unless it's zero
+    pub fn rearm(&self, after: Duration, period: Duration) -> crate::handle::Result<()> {
+        self.handle
+            .write(
+                &[],
+                &[after.as_nanos() as usize, period.as_nanos() as usize],
+            )
+            .map(|_| ())
+    }
+}
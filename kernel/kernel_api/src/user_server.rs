@@ -0,0 +1,91 @@
+//! Lets a process serve handles to other processes, or connect to a process that does
+
+use alloc::vec::Vec;
+
+use flat_bytes::Flat;
+use kernel_syscall_abi::user_server::{
+    UserServerError, UserServerOpenMode, UserServerReplyHeader, UserServerRequestHeader,
+};
+
+use crate::{syscall_return::AsResult, Handle};
+
+pub type Result<T> = core::result::Result<T, UserServerError>;
+
+fn open(name: &str, mode: UserServerOpenMode, options: &[usize]) -> Result<Handle> {
+    let mut params = [0; 6];
+    params[0..3].copy_from_slice(&[
+        name.as_bytes().as_ptr() as *const u8 as usize,
+        name.as_bytes().len(),
+        mode as usize,
+    ]);
+    params[3..options.len() + 3].copy_from_slice(options);
+    Handle::open(5, &params[..]).map_err(|s| s.as_result())
+}
+
+/// Opens a handle to the process serving `name`. Up to 3 options are passed to the server
+pub fn connect(name: &str, options: &[usize]) -> Result<Handle> {
+    open(name, UserServerOpenMode::Connect, options)
+}
+
+pub struct UserServer {
+    handle: Handle,
+    buffer: Vec<u8>,
+}
+
+impl UserServer {
+    /// Registers this process as the server for `name`
+    pub fn register(name: &str) -> Result<Self> {
+        let mut buffer = Vec::new();
+        buffer.resize(4096, 0);
+        Ok(Self {
+            handle: open(name, UserServerOpenMode::Serve, &[])?,
+            buffer,
+        })
+    }
+
+    /// Blocks until a client makes a request. Returns the request and the data that was sent with it
+    pub fn next_request(&mut self) -> Result<(UserServerRequestHeader, &[u8])> {
+        let read = loop {
+            match self.handle.read(&mut self.buffer, &[]) {
+                Ok(read) => break read,
+                Err(e) => match e.as_result() {
+                    UserServerError::BufferTooSmall => {
+                        let new_len = self.buffer.len() * 2;
+                        self.buffer.resize(new_len, 0);
+                    }
+                    e => return Err(e),
+                },
+            }
+        };
+        let (header, size) = UserServerRequestHeader::deserialize_with_size(&self.buffer[..read])
+            .ok_or(UserServerError::InvalidMessage)?;
+        Ok((header, &self.buffer[size..read]))
+    }
+
+    /// Completes a request. `data` is only used for Read requests
+    pub fn reply(
+        &self,
+        request_id: usize,
+        result: core::result::Result<usize, usize>,
+        data: &[u8],
+    ) -> Result<()> {
+        let header = match result {
+            Ok(result) => UserServerReplyHeader {
+                request_id,
+                result,
+                error: 0,
+            },
+            Err(error) => UserServerReplyHeader {
+                request_id,
+                result: 0,
+                error,
+            },
+        };
+        let mut packet = header.serialize();
+        packet.extend_from_slice(data);
+        self.handle
+            .write(&packet, &[])
+            .map(|_| ())
+            .map_err(|s| s.as_result())
+    }
+}
@@ -87,6 +87,15 @@ impl<T> AsyncRwLock<T> {
         RwLockWriteFuture { rwlock: self }
     }
 
+    /// Locks for writing if that can be done right away, for code that can't await
+    pub fn try_write(&self) -> Option<AsyncRwLockWriteGuard<'_, T>> {
+        if self.lock.locked.try_lock_exclusive() {
+            Some(AsyncRwLockWriteGuard { rwlock: self })
+        } else {
+            None
+        }
+    }
+
     unsafe fn force_unlock_exclusive(&self) {
         self.lock.locked.unlock_exclusive();
         for waker in self.lock.wakers.lock().iter() {
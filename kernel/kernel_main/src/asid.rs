@@ -0,0 +1,212 @@
+//! Address space identifiers
+//!
+//! The TLB tags translations with the ASID in satp, so switching between address spaces with different
+//! ASIDs doesn't need a TLB flush. There are only a few ASIDs (at most 2^16, possibly none), so they get recycled:
+//! when they run out, a new generation starts. Address spaces that are running keep their ASID,
+//! the rest get a new one the next time they're switched to, and all TLBs get flushed once.
+//!
+//! ASID 0 is used by the kernel, and by address spaces that couldn't get an ASID of their own.
+//! Those need a full TLB flush when switching to them.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    cpu::{self, load_hartid},
+    hart::{get_this_hart_meta, HART_META},
+    lock::shared::Mutex,
+    paging::satp_root_table,
+    tlb,
+};
+
+pub const SATP_ASID_SHIFT: usize = 44;
+pub const SATP_ASID_MASK: usize = (1 << 16) - 1;
+
+/// How many ASID bits the hardware implements
+static ASID_BITS: AtomicUsize = AtomicUsize::new(0);
+
+struct AddressSpace {
+    asid: usize,
+    generation: u64,
+    /// Harts that might have translations of this address space cached (bit N = hart ID N)
+    harts: usize,
+}
+
+struct AsidAllocator {
+    generation: u64,
+    /// ASIDs given out in this generation
+    used: BTreeSet<usize>,
+    /// Where to start looking for a free ASID
+    next: usize,
+    /// Address spaces by root table address
+    spaces: BTreeMap<usize, AddressSpace>,
+}
+
+static ALLOCATOR: Mutex<AsidAllocator> = Mutex::new(AsidAllocator {
+    generation: 0,
+    used: BTreeSet::new(),
+    next: 1,
+    spaces: BTreeMap::new(),
+});
+
+pub fn asid_of(satp: usize) -> usize {
+    (satp >> SATP_ASID_SHIFT) & SATP_ASID_MASK
+}
+
+pub fn with_asid(satp: usize, asid: usize) -> usize {
+    (satp & !(SATP_ASID_MASK << SATP_ASID_SHIFT)) | (asid & SATP_ASID_MASK) << SATP_ASID_SHIFT
+}
+
+pub fn hart_bit(hart_id: usize) -> usize {
+    // Masks can't represent harts beyond the word size, so assume every hart is included
+    if hart_id >= usize::BITS as usize {
+        usize::MAX
+    } else {
+        1 << hart_id
+    }
+}
+
+/// Finds out how many ASID bits there are, by writing all ones to the ASID field and reading it back.
+/// Must be run after paging is enabled
+pub fn init() {
+    let satp = cpu::read_satp();
+    unsafe { cpu::write_satp(with_asid(satp, SATP_ASID_MASK)) };
+    let bits = asid_of(cpu::read_satp()).count_ones() as usize;
+    unsafe { cpu::write_satp(satp) };
+    unsafe { asm!("sfence.vma") };
+    ASID_BITS.store(bits, Ordering::Release);
+    info!("{} ASID bits available", bits);
+}
+
+impl AsidAllocator {
+    fn asid_count(&self) -> usize {
+        1 << ASID_BITS.load(Ordering::Acquire)
+    }
+
+    fn allocate(&mut self) -> Option<usize> {
+        let count = self.asid_count();
+        for offset in 0..count {
+            let asid = (self.next + offset) % count;
+            if asid != 0 && !self.used.contains(&asid) {
+                self.used.insert(asid);
+                self.next = asid + 1;
+                return Some(asid);
+            }
+        }
+        None
+    }
+
+    /// Starts a new generation. Address spaces that are active on a hart keep their ASID
+    fn new_generation(&mut self) {
+        self.generation += 1;
+        self.used.clear();
+        let active_roots: BTreeSet<usize> = HART_META
+            .read()
+            .values()
+            .map(|meta| satp_root_table(meta.active_satp.load(Ordering::Acquire)))
+            .collect();
+        for (root, space) in self.spaces.iter_mut() {
+            if active_roots.contains(root) && space.asid != 0 {
+                self.used.insert(space.asid);
+                space.generation = self.generation;
+            }
+        }
+        debug!("Starting ASID generation {}", self.generation);
+
+        // Translations with recycled ASIDs must not survive anywhere
+        tlb::flush_local(0, usize::MAX, None);
+        tlb::flush_remote(usize::MAX, 0, usize::MAX, None);
+    }
+
+    /// Gives the address space an ASID from the current generation, if it doesn't have one
+    fn refresh(&mut self, root: usize) {
+        if self.spaces[&root].generation == self.generation {
+            return;
+        }
+        let asid = match self.allocate() {
+            Some(asid) => asid,
+            None => {
+                self.new_generation();
+                if self.spaces[&root].generation == self.generation {
+                    return;
+                }
+                // If even that's not enough, share ASID 0
+                self.allocate().unwrap_or(0)
+            }
+        };
+        let generation = self.generation;
+        let space = self.spaces.get_mut(&root).unwrap();
+        space.asid = asid;
+        space.generation = generation;
+        // Translations with the old ASID were flushed when its generation ended
+        space.harts = 0;
+    }
+}
+
+/// Allocates an ASID for the address space whose root table is in `satp`, and returns `satp` with the ASID in it
+pub fn register(satp: usize) -> usize {
+    let root = satp_root_table(satp);
+    let mut allocator = ALLOCATOR.lock();
+    let stale_generation = allocator.generation.wrapping_sub(1);
+    allocator.spaces.insert(
+        root,
+        AddressSpace {
+            asid: 0,
+            generation: stale_generation,
+            harts: 0,
+        },
+    );
+    allocator.refresh(root);
+    with_asid(satp, allocator.spaces[&root].asid)
+}
+
+/// Gives the ASID back, once the address space won't be used anymore.
+/// Its translations must have been flushed from all harts already (see `tlb::shootdown`)
+pub fn release(satp: usize) {
+    let root = satp_root_table(satp);
+    let mut allocator = ALLOCATOR.lock();
+    let space = match allocator.spaces.remove(&root) {
+        Some(space) => space,
+        None => return,
+    };
+    if space.generation == allocator.generation && space.asid != 0 {
+        allocator.used.remove(&space.asid);
+    }
+}
+
+/// Must be called before this hart switches to `satp`. Returns the satp to use,
+/// which has a different ASID if the address space's ASID was recycled
+pub fn activate(satp: usize) -> usize {
+    let root = satp_root_table(satp);
+    let mut allocator = ALLOCATOR.lock();
+    let satp = if allocator.spaces.contains_key(&root) {
+        allocator.refresh(root);
+        let space = allocator.spaces.get_mut(&root).unwrap();
+        space.harts |= hart_bit(load_hartid());
+        with_asid(satp, space.asid)
+    } else {
+        // Kernel tables don't have an ASID of their own
+        satp
+    };
+    // This is done while holding the lock so that new generations see which address spaces are running
+    if let Some(meta) = get_this_hart_meta() {
+        meta.active_satp.store(satp, Ordering::Release);
+    }
+    satp
+}
+
+/// The ASID of the address space with this root table, and the harts that might have it cached
+pub fn lookup(root: usize) -> Option<(usize, usize)> {
+    let allocator = ALLOCATOR.lock();
+    allocator.spaces.get(&root).map(|space| {
+        if space.generation == allocator.generation {
+            (space.asid, space.harts)
+        } else {
+            // Nothing cached since the TLBs were flushed at the end of its generation
+            (space.asid, 0)
+        }
+    })
+}
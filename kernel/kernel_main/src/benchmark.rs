@@ -1,6 +1,6 @@
 use alloc::{collections::BTreeSet, vec::Vec};
 
-use kernel_cpu::read_time;
+use crate::time::Instant;
 
 pub fn primes(up_to: usize) {
     let mut sieve = Vec::new();
@@ -35,11 +35,9 @@ pub fn primes(up_to: usize) {
 }
 
 pub fn time_fn<T, F: Fn() -> T>(f: F) -> (core::time::Duration, T) {
-    let start_time = read_time();
+    let start_time = Instant::now();
     let ret: T = f();
-    let end_time = read_time();
-
-    let time = core::time::Duration::from_nanos(((end_time - start_time) * 100) as u64);
+    let time = start_time.elapsed();
 
     println!("{:?} secs", (time.as_micros() as f64) / 1000000f64);
 
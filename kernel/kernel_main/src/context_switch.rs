@@ -1,12 +1,9 @@
 //! Switch to processes without leaking memory
 
 use alloc::sync::Arc;
+use core::sync::atomic::Ordering;
 
-use crate::{
-    cpu,
-    process::{self, ProcessState},
-    scheduler,
-};
+use crate::{cpu, cpu_time, hart::get_this_hart_meta, process, scheduler};
 
 /// Trigger a context switch. Must be called from an interrupt context.
 pub fn context_switch(pid: &usize) -> ! {
@@ -21,8 +18,23 @@ pub fn context_switch(pid: &usize) -> ! {
     // We'll also decrement the read count of the Arc to prevent memory leaks
     let lock = process::try_get_process(pid);
 
+    // Whatever this hart was doing until now is over
+    let activity = cpu_time::activity_for(*pid, lock.read().is_supervisor);
+    cpu_time::switch_activity(activity);
+
     let mut guard = lock.write();
 
+    if crate::signal::deliver_pending_signals(&mut guard) {
+        drop(guard);
+        drop(lock);
+        process::exit_process(*pid, kernel_syscall_abi::signal::KILLED_BY_SIGNAL);
+        schedule_and_switch()
+    }
+
+    // Other harts need to know which address space this one uses to shoot down its TLB entries.
+    // This also gives the process a new ASID if its old one got recycled
+    guard.trap_frame.satp = crate::asid::activate(guard.trap_frame.satp);
+
     // Unlock the write lock
     unsafe { lock.force_unlock_write() };
     // Decrement the Arc refcount
@@ -40,18 +52,34 @@ pub fn make_this_process_pending() {
         None => {
             // probably a boot process
         }
-        Some(p) => p.write().state = ProcessState::Pending,
+        Some(p) => scheduler::make_pending(&mut p.write()),
     }
 }
 
 pub fn schedule_and_switch() -> ! {
-    let new_pid = scheduler::schedule();
+    let meta = get_this_hart_meta().unwrap();
+    let mut new_pid = scheduler::schedule();
     if new_pid == 0 {
+        // Say that this hart is idle before checking again, so that a hart that queues
+        // a process meanwhile either sees it idle and sends an IPI, or gets its process scheduled here
+        meta.is_idle.store(true, Ordering::SeqCst);
+        let now = crate::time::Instant::now().ticks();
+        if meta
+            .idle_since
+            .compare_exchange(u64::MAX, now, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+        {
+            crate::hart::schedule_suspend(now);
+        }
+        new_pid = scheduler::schedule();
+    }
+    if new_pid == 0 {
+        drop(meta);
         // Nothing left to schedule
         // Check if it's just that all processes have yielded or that they have been deleted
         if process::useful_process_count() == 0 {
             info!("No processes alive, nothing left to schedule!");
-            crate::sbi::shutdown(0);
+            crate::sbi::shutdown(crate::sbi::ResetReason::NoReason);
             loop {}
         } else {
             // Just wait for something to happen.
@@ -60,5 +88,11 @@ pub fn schedule_and_switch() -> ! {
         }
     }
 
+    meta.is_idle.store(false, Ordering::SeqCst);
+    if meta.idle_since.swap(u64::MAX, Ordering::AcqRel) != u64::MAX {
+        crate::timer_queue::cancel(crate::timer_queue::TimerEventCause::IdleSuspend);
+    }
+    drop(meta);
+    scheduler::start_slice_for(&new_pid);
     context_switch(&new_pid)
 }
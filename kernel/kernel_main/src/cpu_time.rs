@@ -0,0 +1,96 @@
+//! CPU time accounting
+//!
+//! Each hart remembers what it has been doing since when. Whenever that changes (on trap entry and exit,
+//! and on context switches), the elapsed time is charged to a process as user or kernel time,
+//! or to the hart's idle time.
+
+use core::sync::atomic::Ordering;
+
+use crate::{hart::get_this_hart_meta, process::weak_get_process, scheduler, timeout::get_time};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    // Running the user code of a process
+    User(usize),
+    // Running a supervisor process, or handling a trap for a process
+    Kernel(usize),
+    // Running this hart's idle process
+    Idle,
+}
+
+#[derive(Debug)]
+pub struct HartCpuTime {
+    activity: Activity,
+    since: u64,
+    /// In timer ticks
+    pub idle_time: u64,
+}
+
+impl HartCpuTime {
+    pub const fn new() -> Self {
+        Self {
+            activity: Activity::Idle,
+            since: 0,
+            idle_time: 0,
+        }
+    }
+}
+
+/// What running (or handling a trap for) the process `pid` counts as.
+/// Harts that aren't running any process (like when they wait in their boot frame) are idle
+pub fn activity_for(pid: usize, in_kernel: bool) -> Activity {
+    if weak_get_process(&pid).strong_count() == 0 {
+        return Activity::Idle;
+    }
+    let is_idle_process = get_this_hart_meta()
+        .and_then(|meta| meta.get_idle_process())
+        .map(|idle| idle == pid)
+        .unwrap_or(false);
+    if is_idle_process {
+        Activity::Idle
+    } else if in_kernel {
+        Activity::Kernel(pid)
+    } else {
+        Activity::User(pid)
+    }
+}
+
+/// Charges the time since the last call to whatever this hart was doing, and starts counting for `activity`.
+/// The process that gets charged must not be locked for writing
+pub fn switch_activity(activity: Activity) {
+    let meta = match get_this_hart_meta() {
+        Some(meta) => meta,
+        // Early boot
+        None => return,
+    };
+    let now = get_time();
+    let (previous, elapsed) = {
+        let mut cpu_time = meta.cpu_time.lock();
+        let previous = cpu_time.activity;
+        let elapsed = now.saturating_sub(cpu_time.since);
+        cpu_time.activity = activity;
+        cpu_time.since = now;
+        if previous == Activity::Idle {
+            cpu_time.idle_time += elapsed;
+        }
+        (previous, elapsed)
+    };
+    let (pid, user) = match previous {
+        Activity::User(pid) => (pid, true),
+        Activity::Kernel(pid) => (pid, false),
+        Activity::Idle => return,
+    };
+    // The process might be gone already
+    if let Some(process) = weak_get_process(&pid).upgrade() {
+        let process = process.read();
+        process.virtual_runtime.fetch_add(
+            scheduler::virtual_runtime_for(elapsed, process.nice),
+            Ordering::Relaxed,
+        );
+        if user {
+            process.user_time.fetch_add(elapsed, Ordering::Relaxed);
+        } else {
+            process.kernel_time.fetch_add(elapsed, Ordering::Relaxed);
+        }
+    }
+}
@@ -129,8 +129,35 @@ pub fn setup_devices() {
                     }
                 }
                 &"ns16550a" => {
-                    // Create UART device
-                    //let uart_dev = unsafe { crate::drivers::uart::Uart::new(node.unit_address.unwrap_or(0)) }.get().unwrap();
+                    // Feed received bytes to the console
+                    if let Some(PropertyValue::u32(interrupt_id)) =
+                        node.properties.get("interrupts")
+                    {
+                        let address =
+                            new_virtual_buffer(node.unit_address.unwrap_or(0x1000_0000), 0x1000);
+                        unsafe { crate::drivers::uart::Uart::new(address) }.setup();
+                        let handler = ExternalInterruptHandler::new(
+                            (*interrupt_id).try_into().unwrap(),
+                            alloc::sync::Arc::new(move |_id| {
+                                unsafe { crate::drivers::uart::Uart::new(address) }
+                                    .receive_all(crate::drivers::uart::console::on_receive);
+                            }),
+                        );
+                        *node.kernel_struct.write() = Some(alloc::boxed::Box::new(handler));
+                    }
+                }
+                &"google,goldfish-rtc" => {
+                    // There's no default address for the RTC, so it can't be used without one
+                    if let Some(unit_address) = node.unit_address {
+                        let address = new_virtual_buffer(unit_address, 0x1000);
+                        let rtc =
+                            unsafe { crate::drivers::goldfish_rtc::GoldfishRtc::new(address) };
+                        crate::time::set_realtime(rtc.now());
+                        info!("Realtime clock: {:?} since the epoch", rtc.now());
+                        *node.kernel_struct.write() = Some(alloc::boxed::Box::new(rtc));
+                    } else {
+                        warn!("Skipping goldfish-rtc device without an address");
+                    }
                 }
                 _ => {
                     warn!(
@@ -144,16 +171,6 @@ pub fn setup_devices() {
     drop(lock);
 
     // fdt::root().read().pretty(0);
-    /*
-    let _handler2 = ExternalInterruptHandler::new(
-        10,
-        alloc::sync::Arc::new(|_id| {
-            let c = unsafe { crate::drivers::uart::Uart::new(0x10000000) }
-                .get()
-                .unwrap();
-            print!("C {}", c)
-        }),
-    );*/
 
     info!("Finished device setup");
 
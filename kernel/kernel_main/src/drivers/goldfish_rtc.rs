@@ -0,0 +1,44 @@
+//! Driver for the Goldfish real time clock, present on QEMU's virt machine
+//! See https://android.googlesource.com/platform/external/qemu/+/master/docs/GOLDFISH-VIRTUAL-HARDWARE.TXT
+
+use core::time::Duration;
+
+use volatile_register::{RO, RW};
+
+#[repr(C)]
+struct GoldfishRtcRegisters {
+    // Reading the low half latches the high half, so it has to be read first
+    time_low: RO<u32>,
+    time_high: RO<u32>,
+    alarm_low: RW<u32>,
+    alarm_high: RW<u32>,
+    irq_enabled: RW<u32>,
+    clear_alarm: RW<u32>,
+    alarm_status: RO<u32>,
+    clear_interrupt: RW<u32>,
+}
+
+pub struct GoldfishRtc {
+    registers: *mut GoldfishRtcRegisters,
+}
+
+// The registers are only read, and reading them is atomic
+unsafe impl Send for GoldfishRtc {}
+unsafe impl Sync for GoldfishRtc {}
+
+impl GoldfishRtc {
+    /// SAFETY: `address` has to be the (mapped) base address of a Goldfish RTC
+    pub unsafe fn new(address: usize) -> Self {
+        Self {
+            registers: address as *mut GoldfishRtcRegisters,
+        }
+    }
+
+    /// Time since the Unix epoch
+    pub fn now(&self) -> Duration {
+        let registers = unsafe { &*self.registers };
+        let low = registers.time_low.read() as u64;
+        let high = registers.time_high.read() as u64;
+        Duration::from_nanos(high << 32 | low)
+    }
+}
@@ -1,3 +1,4 @@
+pub mod goldfish_rtc;
 pub mod traits;
 pub mod uart;
 pub mod virtio;
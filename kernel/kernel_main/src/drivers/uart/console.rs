@@ -0,0 +1,186 @@
+//! Kernel-side console input. The UART interrupt handler feeds received bytes into here,
+//! and the console handle backend reads them out.
+//!
+//! In cooked mode, input is echoed and can be edited with backspace and Ctrl+U until a newline is typed,
+//! and only then it becomes readable. In raw mode, every byte becomes readable as soon as it arrives.
+
+use alloc::vec::Vec;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use kernel_syscall_abi::console::ConsoleMode;
+
+use crate::lock::shared::Mutex;
+
+const INPUT_BUFFER_SIZE: usize = 1024;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const CTRL_U: u8 = 0x15;
+
+/// Fixed-size FIFO of bytes. Bytes that arrive when it's full are dropped
+struct RingBuffer {
+    data: [u8; INPUT_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; INPUT_BUFFER_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == INPUT_BUFFER_SIZE {
+            return false;
+        }
+        self.data[(self.start + self.len) % INPUT_BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.data[self.start];
+        self.start = (self.start + 1) % INPUT_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+struct Console {
+    mode: ConsoleMode,
+    // Bytes that can be read
+    input: RingBuffer,
+    // The line that is being edited in cooked mode
+    line: Vec<u8>,
+    readers: Vec<Waker>,
+}
+
+static CONSOLE: Mutex<Console> = Mutex::new(Console {
+    mode: ConsoleMode::Cooked,
+    input: RingBuffer::new(),
+    line: Vec::new(),
+    readers: Vec::new(),
+});
+
+fn echo(bytes: &[u8]) {
+    let _ = crate::std_macros::get_uart().write_bytes(bytes);
+}
+
+impl Console {
+    fn wake_readers(&mut self) {
+        for waker in core::mem::take(&mut self.readers) {
+            waker.wake();
+        }
+    }
+
+    fn receive(&mut self, byte: u8) {
+        match self.mode {
+            ConsoleMode::Raw => {
+                self.input.push(byte);
+                self.wake_readers();
+            }
+            ConsoleMode::Cooked => match byte {
+                b'\r' | b'\n' => {
+                    echo(b"\r\n");
+                    for byte in core::mem::take(&mut self.line) {
+                        self.input.push(byte);
+                    }
+                    self.input.push(b'\n');
+                    self.wake_readers();
+                }
+                BACKSPACE | DELETE => {
+                    if self.line.pop().is_some() {
+                        echo(b"\x08 \x08");
+                    }
+                }
+                CTRL_U => {
+                    for _ in self.line.drain(..) {
+                        echo(b"\x08 \x08");
+                    }
+                }
+                byte => {
+                    // Leave room for the newline at the end
+                    if self.line.len() + self.input.len + 1 < INPUT_BUFFER_SIZE {
+                        self.line.push(byte);
+                        echo(&[byte]);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Called by the UART interrupt handler for every byte received
+pub fn on_receive(byte: u8) {
+    CONSOLE.lock().receive(byte);
+}
+
+/// Changes the console mode. When switching to raw mode, the line being edited becomes readable
+pub fn set_mode(mode: ConsoleMode) {
+    let mut console = CONSOLE.lock();
+    if mode == ConsoleMode::Raw {
+        for byte in core::mem::take(&mut console.line) {
+            console.input.push(byte);
+        }
+        console.wake_readers();
+    }
+    console.mode = mode;
+}
+
+/// Copies readable bytes into `buf`. In cooked mode, this stops after the end of a line
+fn take_input(console: &mut Console, buf: &mut [u8]) -> usize {
+    let mut count = 0;
+    while count < buf.len() {
+        match console.input.pop() {
+            Some(byte) => {
+                buf[count] = byte;
+                count += 1;
+                if console.mode == ConsoleMode::Cooked && byte == b'\n' {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    count
+}
+
+/// Future that waits until there is input and then reads it into the buffer
+pub struct ReadFuture<'buf> {
+    buf: &'buf mut [u8],
+}
+
+impl<'buf> Future for ReadFuture<'buf> {
+    type Output = usize;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.buf.is_empty() {
+            return Poll::Ready(0);
+        }
+        let mut console = CONSOLE.lock();
+        if console.input.is_empty() {
+            console.readers.push(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(take_input(&mut console, &mut self.buf[..]))
+        }
+    }
+}
+
+pub fn read(buf: &mut [u8]) -> ReadFuture<'_> {
+    ReadFuture { buf }
+}
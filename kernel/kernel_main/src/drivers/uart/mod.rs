@@ -1,3 +1,4 @@
+pub mod console;
 pub mod ns16550a;
 
 pub use ns16550a::Ns16550a as Uart;
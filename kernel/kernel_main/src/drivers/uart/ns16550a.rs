@@ -62,6 +62,8 @@ impl Ns16550a {
 
     pub fn setup(&mut self) {
         unsafe {
+            // Enable the FIFOs, so that bytes don't get lost while we handle the interrupt
+            (*self.registers).fifo_interrupt.write(1);
             (*self.registers).interrupt_enable.write(
                 (*self.registers).interrupt_enable.read()
                     | Ns16550aInterruptEnableRegister::ReadAvailable as u8,
@@ -88,6 +90,14 @@ impl Ns16550a {
         }
     }
 
+    /// Reads all bytes in the receive FIFO. This is what should be done on a ReadAvailable interrupt,
+    /// since the interrupt stays pending until the FIFO is empty
+    pub fn receive_all(&mut self, mut on_byte: impl FnMut(u8)) {
+        while let Some(byte) = self.get() {
+            on_byte(byte)
+        }
+    }
+
     pub fn write_bytes(&mut self, s: &[u8]) -> fmt::Result {
         for byte in s.iter() {
             self.put(*byte)
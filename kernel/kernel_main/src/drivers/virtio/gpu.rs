@@ -6,7 +6,7 @@ use alloc::{
 use core::mem::{size_of, MaybeUninit};
 
 use kernel_lock::shared::{Mutex, RwLock};
-use kernel_util::{boxed_slice_with_alignment_uninit, struct_to_bytes};
+use kernel_util::struct_to_bytes;
 
 use super::{
     future_util::{FutureVirtioDeviceType, WrappedVirtioDeviceType},
@@ -43,13 +43,19 @@ type Pixel = ColorR8G8B8A8Unorm;
 pub struct Screen {
     width: u32,
     height: u32,
-    buffer: Box<[MaybeUninit<Pixel>]>,
+    /// Frames from the frame allocator. The host keeps using them as the resource's backing,
+    /// so they're never freed
+    buffer: &'static mut [MaybeUninit<Pixel>],
 }
 
 impl Screen {
     fn new(width: u32, height: u32) -> Self {
-        let mut buffer: Box<[MaybeUninit<Pixel>]> =
-            boxed_slice_with_alignment_uninit((width * height) as usize * size_of::<Pixel>(), 4096);
+        let pixels = (width * height) as usize;
+        let length = pixels * size_of::<Pixel>();
+        let address = crate::frame_allocator::allocate(crate::frame_allocator::order_for(length))
+            .expect("Out of memory for the framebuffer");
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(address as *mut MaybeUninit<Pixel>, pixels) };
         Self {
             width,
             height,
@@ -6,7 +6,7 @@ pub mod gpu;
 pub mod net;
 
 use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
-use core::{alloc::Layout, convert::TryInto, future::Future, mem::MaybeUninit, slice, task::Waker};
+use core::{convert::TryInto, future::Future, mem::MaybeUninit, slice, task::Waker};
 
 use itertools::Itertools;
 use volatile_register::{RO, RW, WO};
@@ -115,7 +115,7 @@ unsafe impl Send for VirtioDevice {}
 #[derive(Debug)]
 pub struct SplitVirtqueue {
     // See section 2.6 of https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-230005
-    /// This pointer was allocated with Box::leak() and will then be reconstructed Box:from_raw before dropping
+    /// This pointer points to frames from the frame allocator, which are released on drop
     /// The layout of the data pointed to by this pointer is:
     /// Virtqueue Part      Alignment   Size
     /// Descriptor Table    16          16∗(Queue Size)
@@ -219,16 +219,11 @@ impl SplitVirtqueue {
         Self::used_ring_offset(size) + Self::align(Self::used_ring_size(size))
     }
     fn new(size: u16) -> SplitVirtqueue {
-        use core::alloc::GlobalAlloc;
-
-        use crate::allocator::ALLOCATOR;
-
-        // Allocate page-aligned memory
+        // Allocate page-aligned, zeroed memory
         let mem_size = Self::memory_size(&size);
-        let pointer = unsafe { ALLOCATOR.alloc(Layout::from_size_align(mem_size, 4096).unwrap()) };
-
-        // Zero out the memroy
-        unsafe { slice::from_raw_parts_mut(pointer, mem_size).fill(0) }
+        let pointer =
+            crate::frame_allocator::allocate_zeroed(crate::frame_allocator::order_for(mem_size))
+                .expect("Out of memory for virtqueue") as *mut u8;
 
         SplitVirtqueue {
             pointer: pointer as _,
@@ -511,7 +506,7 @@ impl Future for VirtioDevice {
 
 impl Drop for SplitVirtqueue {
     fn drop(&mut self) {
-        unsafe { drop(Box::from_raw(self.pointer)) }
+        crate::frame_allocator::release(self.pointer as usize);
     }
 }
 
@@ -5,6 +5,7 @@ use alloc::{
     collections::{BTreeMap, VecDeque},
     format,
     string::String,
+    vec::Vec,
 };
 use core::{any::Any, mem::MaybeUninit};
 
@@ -134,6 +135,40 @@ impl Node {
         }
     }
 
+    /// The value of a cell count property like #address-cells
+    pub fn cell_count(&self, name: &str) -> Option<usize> {
+        match self.properties.get(name) {
+            Some(PropertyValue::u32(count)) => Some(*count as usize),
+            _ => None,
+        }
+    }
+
+    /// The (address, size) pairs in the reg property.
+    /// The cell counts are the #address-cells and #size-cells of the parent node
+    pub fn reg(&self, address_cells: usize, size_cells: usize) -> Vec<(usize, usize)> {
+        let bytes: Vec<u8> = match self.properties.get("reg") {
+            Some(PropertyValue::PropSpecific(bytes)) => bytes.to_vec(),
+            Some(PropertyValue::u64(value)) => value.to_be_bytes().to_vec(),
+            Some(PropertyValue::u32(value)) => value.to_be_bytes().to_vec(),
+            _ => return Vec::new(),
+        };
+        if address_cells + size_cells == 0 {
+            return Vec::new();
+        }
+        let read_cells = |cells: &[u8]| {
+            cells
+                .iter()
+                .fold(0usize, |value, byte| value << 8 | *byte as usize)
+        };
+        bytes
+            .chunks_exact((address_cells + size_cells) * 4)
+            .map(|entry| {
+                let (address, size) = entry.split_at(address_cells * 4);
+                (read_cells(address), read_cells(size))
+            })
+            .collect()
+    }
+
     /// The lifetimes for this function aren't <'static> because that would be an aliasing rule violation
     /// (closure mutably borrows Node forever so no one else can mut borrow it again )
     pub fn walk_mut<F: FnMut(&mut Node)>(&mut self, closure: &mut F) {
@@ -385,6 +420,13 @@ pub fn link_phandles() {
         });
 }
 
+/// The address and size of the device tree blob, which has to stay in memory
+pub fn blob_range() -> (usize, usize) {
+    let base = unsafe { DEVICE_TREE_BASE };
+    let size = unsafe { (*base).total_size.swap_bytes() } as usize;
+    (base as usize, size)
+}
+
 pub fn init(header_addr: *const FdtHeader) -> &'static RwLock<Node> {
     unsafe { DEVICE_TREE_BASE = header_addr };
     let token_addr = unsafe {
@@ -243,7 +243,15 @@ impl Ext2 {
                 .clone()
         })
     }
-    pub async fn write_inode(&self, inode: u32, value: &Inode) -> Result<()> {
+    /// Writes the inode to the disk after its contents changed, updating its modification and
+    /// change timestamps to the current time if it's known. The access time is left alone
+    pub async fn write_inode(&self, inode: u32, value: &mut Inode) -> Result<()> {
+        if let Some(now) = crate::time::realtime() {
+            let now = now.as_secs() as u32;
+            value.mtime = now;
+            value.ctime = now;
+        }
+
         let inode_table_block = self
             .read_block_group_descriptor(self.get_inode_block_group(inode))
             .await?
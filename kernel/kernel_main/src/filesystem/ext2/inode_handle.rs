@@ -86,7 +86,7 @@ impl InodeHandleState {
                 .await?;
         }
 
-        fs.write_inode(self.inode_number, &self.inode).await?;
+        fs.write_inode(self.inode_number, &mut self.inode).await?;
 
         Ok(position_in_buffer)
     }
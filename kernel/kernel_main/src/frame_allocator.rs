@@ -0,0 +1,255 @@
+//! Allocates physical memory in page-sized frames, separately from the kernel heap
+//!
+//! This manages the RAM that the device tree's /memory nodes report, minus the kernel image,
+//! the kernel heap, the device tree blob and /reserved-memory regions.
+//! It's a buddy allocator: blocks are 2^order frames big and aligned to their size,
+//! and a freed block gets merged with its buddy if the buddy is free too.
+//!
+//! Allocated blocks are reference counted, so that memory mapped in several places
+//! only gets freed once the last user releases it.
+
+use alloc::{collections::BTreeSet, vec::Vec};
+use core::ffi::c_void;
+
+use crate::{fdt, lock::shared::Mutex, paging::PAGE_SIZE};
+
+/// The biggest blocks are 2^MAX_ORDER frames (1 GiB)
+pub const MAX_ORDER: usize = 18;
+
+/// Process page tables only identity map RAM up to here (see `virtual_buffers::initialize_root_table`),
+/// and the kernel accesses frames while running with them
+const ADDRESSABLE_END: usize = 0x1_0000_0000;
+
+extern "C" {
+    static _heap_end: c_void;
+}
+
+#[derive(Clone, Copy, Default)]
+struct FrameInfo {
+    /// Zero unless the frame is the first one of an allocated block
+    refcount: u32,
+    order: u8,
+}
+
+/// A contiguous range of managed RAM
+struct Zone {
+    start: usize,
+    end: usize,
+    frames: Vec<FrameInfo>,
+}
+
+struct FrameAllocator {
+    zones: Vec<Zone>,
+    /// Start addresses of the free blocks of each order
+    free_lists: [BTreeSet<usize>; MAX_ORDER + 1],
+    free_frames: usize,
+    total_frames: usize,
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+fn block_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// The smallest order whose blocks fit `size` bytes
+pub fn order_for(size: usize) -> usize {
+    let frames = size.div_ceil(PAGE_SIZE).max(1);
+    frames.next_power_of_two().trailing_zeros() as usize
+}
+
+/// Removes `hole` from the ranges
+fn subtract(ranges: Vec<(usize, usize)>, hole: (usize, usize)) -> Vec<(usize, usize)> {
+    let mut result = Vec::new();
+    for (start, end) in ranges {
+        if hole.1 <= start || hole.0 >= end {
+            result.push((start, end));
+            continue;
+        }
+        if start < hole.0 {
+            result.push((start, hole.0));
+        }
+        if hole.1 < end {
+            result.push((hole.1, end));
+        }
+    }
+    result
+}
+
+impl FrameAllocator {
+    fn frame_info(&mut self, address: usize) -> Option<&mut FrameInfo> {
+        let zone = self
+            .zones
+            .iter_mut()
+            .find(|zone| zone.start <= address && address < zone.end)?;
+        zone.frames.get_mut((address - zone.start) / PAGE_SIZE)
+    }
+
+    /// Adds a range as free blocks, each as big as its alignment allows
+    fn add_range(&mut self, start: usize, end: usize) {
+        let mut address = start;
+        while address < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|order| {
+                    address % block_size(*order) == 0 && address + block_size(*order) <= end
+                })
+                .unwrap();
+            self.free_lists[order].insert(address);
+            address += block_size(order);
+        }
+        self.zones.push(Zone {
+            start,
+            end,
+            frames: alloc::vec![FrameInfo::default(); (end - start) / PAGE_SIZE],
+        });
+        self.free_frames += (end - start) / PAGE_SIZE;
+        self.total_frames += (end - start) / PAGE_SIZE;
+    }
+
+    fn allocate(&mut self, order: usize) -> Option<usize> {
+        let mut current_order = (order..=MAX_ORDER).find(|o| !self.free_lists[*o].is_empty())?;
+        let address = self.free_lists[current_order].pop_first()?;
+        // Split the block, giving the upper halves back
+        while current_order > order {
+            current_order -= 1;
+            self.free_lists[current_order].insert(address + block_size(current_order));
+        }
+        let info = self.frame_info(address).unwrap();
+        info.refcount = 1;
+        info.order = order as u8;
+        self.free_frames -= 1 << order;
+        Some(address)
+    }
+
+    fn free(&mut self, mut address: usize, mut order: usize) {
+        self.free_frames += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = address ^ block_size(order);
+            if !self.free_lists[order].remove(&buddy) {
+                break;
+            }
+            address = address.min(buddy);
+            order += 1;
+        }
+        self.free_lists[order].insert(address);
+    }
+}
+
+/// Finds the free RAM in the device tree. Must be called after fdt::init
+pub fn init() {
+    let root = fdt::root().read();
+    let address_cells = root.cell_count("#address-cells").unwrap_or(2);
+    let size_cells = root.cell_count("#size-cells").unwrap_or(1);
+
+    let mut ranges: Vec<(usize, usize)> = root
+        .children
+        .get("memory")
+        .into_iter()
+        .flat_map(|nodes| nodes.values())
+        .flat_map(|node| node.reg(address_cells, size_cells))
+        .map(|(start, size)| (start, (start + size).min(ADDRESSABLE_END)))
+        .filter(|(start, end)| start < end)
+        .collect();
+
+    // Firmware, the kernel and its heap
+    ranges = subtract(ranges, (0, unsafe { &_heap_end as *const c_void as usize }));
+    // The FDT is still in use, since nodes borrow strings from it
+    let (fdt_start, fdt_size) = fdt::blob_range();
+    ranges = subtract(ranges, (fdt_start, fdt_start + fdt_size));
+    if let Some(reserved) = root.get("reserved-memory") {
+        let address_cells = reserved
+            .cell_count("#address-cells")
+            .unwrap_or(address_cells);
+        let size_cells = reserved.cell_count("#size-cells").unwrap_or(size_cells);
+        for node in reserved.children() {
+            for (start, size) in node.reg(address_cells, size_cells) {
+                ranges = subtract(ranges, (start, start + size));
+            }
+        }
+    }
+
+    let mut allocator = FrameAllocator {
+        zones: Vec::new(),
+        free_lists: core::array::from_fn(|_| BTreeSet::new()),
+        free_frames: 0,
+        total_frames: 0,
+    };
+    for (start, end) in ranges {
+        let start = start.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let end = end.div_floor(PAGE_SIZE) * PAGE_SIZE;
+        if start < end {
+            info!("Frame allocator: managing {:x}-{:x}", start, end);
+            allocator.add_range(start, end);
+        }
+    }
+    info!(
+        "Frame allocator: {} MiB available",
+        allocator.total_frames * PAGE_SIZE / (1024 * 1024)
+    );
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// Allocates 2^order contiguous frames, and returns the physical address of the first one
+pub fn allocate(order: usize) -> Option<usize> {
+    if order > MAX_ORDER {
+        return None;
+    }
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("Frame allocator not initialized")
+        .allocate(order)
+}
+
+/// Like allocate, but fills the frames with zeroes
+pub fn allocate_zeroed(order: usize) -> Option<usize> {
+    let address = allocate(order)?;
+    // SAFETY: The frames are identity mapped and now belong to us
+    unsafe { core::ptr::write_bytes(address as *mut u8, 0, block_size(order)) };
+    Some(address)
+}
+
+/// Adds a reference to the block starting at `address`.
+/// Returns false if there isn't an allocated block there (for example, if it's MMIO)
+pub fn retain(address: usize) -> bool {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    match lock.as_mut().and_then(|a| a.frame_info(address)) {
+        Some(info) if info.refcount != 0 => {
+            info.refcount += 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Removes a reference to the block starting at `address`, and frees it if it was the last one.
+/// Returns false if there isn't an allocated block there
+pub fn release(address: usize) -> bool {
+    let mut lock = FRAME_ALLOCATOR.lock();
+    let allocator = match lock.as_mut() {
+        Some(allocator) => allocator,
+        None => return false,
+    };
+    let order = match allocator.frame_info(address) {
+        Some(info) if info.refcount != 0 => {
+            info.refcount -= 1;
+            if info.refcount != 0 {
+                return true;
+            }
+            info.order as usize
+        }
+        _ => return false,
+    };
+    allocator.free(address, order);
+    true
+}
+
+/// Free and total bytes of managed RAM
+pub fn usage() -> (usize, usize) {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map(|a| (a.free_frames * PAGE_SIZE, a.total_frames * PAGE_SIZE))
+        .unwrap_or((0, 0))
+}
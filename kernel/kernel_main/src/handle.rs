@@ -1,9 +1,13 @@
-use alloc::{boxed::Box, sync::Weak};
-use core::{fmt::Debug, num::NonZeroUsize};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Weak};
+use core::{
+    fmt::Debug,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use kernel_as_register::EncodedError;
 
-use crate::handle_backends::call_as_register_function;
+use crate::{handle_backends::call_as_register_function, lock::shared::Mutex};
 
 #[repr(usize)]
 pub enum StandardHandleErrors {
@@ -55,18 +59,92 @@ pub trait HandleBackend {
         None
     }
 
+    /// Gives `handle` to the handle `id`, to be stored at `slot`.
+    /// This is used to pass handles to processes that haven't started yet
+    async fn receive_handle(
+        &self,
+        _id: &usize,
+        _slot: usize,
+        handle: Handle,
+    ) -> Result<usize, EncodedError> {
+        let _ = handle.close(&[]);
+        call_as_register_function(async || Err(StandardHandleErrors::Unimplemented as usize)).await
+    }
+
+    /// The PID of the process that the handle `id` refers to, if it refers to one.
+    /// Used to send signals to processes
+    fn process_id(&self, _id: &usize) -> Option<usize> {
+        None
+    }
+
     fn close(&self, _id: &usize, _options: &[usize]) -> Result<(), EncodedError> {
         Ok(())
     }
 }
 
+static NEXT_BACKEND_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Backends are shared between all processes, so the ID that a backend knows a handle by
+/// has to be unique in the whole kernel (unlike fd_id, which is only unique inside a process)
+pub fn allocate_backend_id() -> usize {
+    NEXT_BACKEND_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Returns the lowest fd number above all the ones in use
+pub fn next_fd_number(handles: &BTreeMap<usize, Handle>) -> usize {
+    handles
+        .last_key_value()
+        .map(|s| s.0 + 1)
+        .unwrap_or(kernel_syscall_abi::STDERR_FD + 1)
+}
+
+/// How many handles share each backend ID, for IDs that have been duplicated.
+/// IDs that aren't here are used by a single handle
+static SHARED_BACKEND_IDS: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+
 // 0BSD
 pub struct Handle {
     pub fd_id: usize,
     pub backend: Weak<dyn HandleBackend + Send + Sync>,
+    /// The ID that gets passed to the backend's methods
     pub backend_meta: usize,
 }
 
+impl Handle {
+    /// Creates a handle with the given fd_id that shares the backend state of this one.
+    /// HandleBackend::split isn't used here, because it gives the new handle state of its own
+    /// (like a separate file offset), while both fds have to see the same state
+    pub fn duplicate(&self, fd_id: usize) -> Handle {
+        *SHARED_BACKEND_IDS
+            .lock()
+            .entry(self.backend_meta)
+            .or_insert(1) += 1;
+        Handle {
+            fd_id,
+            backend: self.backend.clone(),
+            backend_meta: self.backend_meta,
+        }
+    }
+
+    /// Closes the handle. The backend is only told about it when this was the last handle using its backend ID
+    pub fn close(self, options: &[usize]) -> Result<(), EncodedError> {
+        {
+            let mut shared = SHARED_BACKEND_IDS.lock();
+            if let Some(count) = shared.get_mut(&self.backend_meta) {
+                *count -= 1;
+                if *count == 1 {
+                    shared.remove(&self.backend_meta);
+                }
+                return Ok(());
+            }
+        }
+        match self.backend.upgrade() {
+            Some(backend) => backend.close(&self.backend_meta, options),
+            None => Ok(()),
+        }
+    }
+}
+
 impl Debug for Handle {
     fn fmt(
         &self,
@@ -74,6 +152,7 @@ impl Debug for Handle {
     ) -> core::result::Result<(), core::fmt::Error> {
         fmt.debug_struct("Handle")
             .field("fd_id", &self.fd_id)
+            .field("backend_meta", &self.backend_meta)
             .field("backend", &self.backend.upgrade().map(|s| s.name()))
             .finish()?;
         Ok(())
@@ -0,0 +1,68 @@
+//! Handle backend for the UART console. Reading blocks until there is input (see `drivers::uart::console`),
+//! and writing outputs to the UART.
+
+use alloc::boxed::Box;
+
+use kernel_as_register::EncodedError;
+use kernel_syscall_abi::console::{ConsoleError, ConsoleMode, ConsoleRequest};
+
+use super::call_as_register_function;
+use crate::{drivers::uart::console, handle::HandleBackend};
+
+pub struct ConsoleHandleBackend;
+
+#[async_trait]
+impl HandleBackend for ConsoleHandleBackend {
+    fn create_singleton() -> alloc::sync::Arc<dyn HandleBackend + Send + Sync>
+    where
+        Self: Sized,
+    {
+        alloc::sync::Arc::new(Self)
+    }
+
+    async fn open(&self, _id: &usize, _options: &[usize]) -> Result<usize, EncodedError> {
+        Ok(0)
+    }
+
+    fn name(&self) -> &'static str {
+        "ConsoleHandleBackend"
+    }
+
+    async fn read(
+        &self,
+        _id: &usize,
+        buf: &mut [u8],
+        _options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        Ok(console::read(buf).await)
+    }
+
+    async fn write(
+        &self,
+        _id: &usize,
+        buf: &[u8],
+        options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        call_as_register_function::<ConsoleError, _, _, _>(async move || {
+            // a3 (Option #0) = ConsoleRequest
+            // a4 (Option #1) = ConsoleMode, for SetMode
+            if options[0] == ConsoleRequest::Output as usize {
+                let _ = crate::std_macros::get_uart().write_bytes(buf);
+                Ok(buf.len())
+            } else if options[0] == ConsoleRequest::SetMode as usize {
+                let mode = if options[1] == ConsoleMode::Cooked as usize {
+                    ConsoleMode::Cooked
+                } else if options[1] == ConsoleMode::Raw as usize {
+                    ConsoleMode::Raw
+                } else {
+                    return Err(ConsoleError::InvalidMode);
+                };
+                console::set_mode(mode);
+                Ok(0)
+            } else {
+                Err(ConsoleError::InvalidRequest)
+            }
+        })
+        .await
+    }
+}
@@ -0,0 +1,126 @@
+//! Handle backend for reading the kernel log buffer (like dmesg) and changing log levels
+
+use alloc::{boxed::Box, collections::BTreeMap};
+
+use flat_bytes::Flat;
+use kernel_as_register::EncodedError;
+use kernel_syscall_abi::kernel_log::{KernelLogError, KernelLogOpenMode, KernelLogRecordHeader};
+
+use super::call_as_register_function;
+use crate::{handle::HandleBackend, lock::shared::RwLock, logger, process::Process};
+
+struct KernelLogReader {
+    // Sequence number of the next record to read
+    next_sequence: usize,
+    follow: bool,
+}
+
+#[derive(Default)]
+pub struct KernelLogHandleBackend {
+    readers: RwLock<BTreeMap<usize, KernelLogReader>>,
+}
+
+#[async_trait]
+impl HandleBackend for KernelLogHandleBackend {
+    fn create_singleton() -> alloc::sync::Arc<dyn HandleBackend + Send + Sync + 'static>
+    where
+        Self: Sized,
+    {
+        alloc::sync::Arc::new(Self::default())
+    }
+
+    async fn open(&self, id: &usize, options: &[usize]) -> Result<usize, EncodedError> {
+        // a1 (Option #0) = KernelLogOpenMode
+        self.readers.write().insert(
+            *id,
+            KernelLogReader {
+                next_sequence: 0,
+                follow: options[0] == KernelLogOpenMode::Follow as usize,
+            },
+        );
+        Ok(0)
+    }
+
+    fn name(&self) -> &'static str {
+        "KernelLogHandleBackend"
+    }
+
+    async fn read(
+        &self,
+        id: &usize,
+        buf: &mut [u8],
+        _options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        call_as_register_function::<KernelLogError, _, _, _>(async move || loop {
+            let (sequence, follow) = {
+                let readers = self.readers.read();
+                let reader = &readers[id];
+                (reader.next_sequence, reader.follow)
+            };
+
+            let mut written = 0;
+            let mut too_small = false;
+            let next_sequence = logger::read_records(sequence, |record| {
+                let header = KernelLogRecordHeader {
+                    sequence: record.sequence,
+                    time: record.time as usize,
+                    hart_id: record.hart_id,
+                    level: record.level as usize,
+                    text_length: record.text().len(),
+                }
+                .serialize();
+                let end = written + header.len() + record.text().len();
+                if end > buf.len() {
+                    too_small = written == 0;
+                    return false;
+                }
+                buf[written..written + header.len()].copy_from_slice(&header);
+                buf[written + header.len()..end].copy_from_slice(record.text());
+                written = end;
+                true
+            });
+            self.readers.write().get_mut(id).unwrap().next_sequence = next_sequence;
+
+            if written != 0 {
+                return Ok(written);
+            } else if too_small {
+                return Err(KernelLogError::BufferTooSmall);
+            } else if !follow {
+                return Ok(0);
+            }
+            logger::wait_for_record(next_sequence).await;
+        })
+        .await
+    }
+
+    async fn write(
+        &self,
+        _id: &usize,
+        buf: &[u8],
+        options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        // This runs in the syscall, so the current process is the caller
+        let allowed = {
+            let process = Process::this();
+            let process = process.read();
+            process.user_id == 0 || process.is_supervisor
+        };
+        call_as_register_function::<KernelLogError, _, _, _>(async move || {
+            if !allowed {
+                return Err(KernelLogError::NoPermission);
+            }
+            // The buffer is the module path, and a3 (Option #0) is the level
+            let module = core::str::from_utf8(buf).map_err(|_| KernelLogError::InvalidModule)?;
+            let level =
+                logger::level_filter_from_usize(options[0]).ok_or(KernelLogError::InvalidLevel)?;
+            logger::set_level(module, level);
+            Ok(buf.len())
+        })
+        .await
+    }
+
+    fn close(&self, id: &usize, _options: &[usize]) -> Result<(), EncodedError> {
+        self.readers.write().remove(id);
+        Ok(())
+    }
+}
@@ -4,15 +4,26 @@ use core::future::Future;
 use kernel_as_register::{AsRegister, EncodedError};
 
 use self::{
-    filesystem::FilesystemHandleBackend, interrupt::InterruptHandleBackend,
+    console::ConsoleHandleBackend, filesystem::FilesystemHandleBackend,
+    interrupt::InterruptHandleBackend, kernel_log::KernelLogHandleBackend,
     log_output::LogOutputHandleBackend, process_egg::ProcessEggBackend,
+    process_info::ProcessInfoHandleBackend, timer::TimerHandleBackend,
+    user_server::UserServerBackend,
+};
+use crate::{
+    handle::{Handle, HandleBackend},
+    lock::shared::RwLock,
 };
-use crate::{handle::HandleBackend, lock::shared::RwLock};
 
+pub mod console;
 pub mod filesystem;
 pub mod interrupt;
+pub mod kernel_log;
 pub mod log_output;
 pub mod process_egg;
+pub mod process_info;
+pub mod timer;
+pub mod user_server;
 
 /// Utility function
 pub async fn call_as_register_function<
@@ -47,6 +58,21 @@ pub fn initialize_constructors() {
     BACKEND_CONSTRUCTORS
         .write()
         .insert(4, InterruptHandleBackend::create_singleton);
+    BACKEND_CONSTRUCTORS
+        .write()
+        .insert(5, UserServerBackend::create_singleton);
+    BACKEND_CONSTRUCTORS
+        .write()
+        .insert(6, ConsoleHandleBackend::create_singleton);
+    BACKEND_CONSTRUCTORS
+        .write()
+        .insert(7, KernelLogHandleBackend::create_singleton);
+    BACKEND_CONSTRUCTORS
+        .write()
+        .insert(8, ProcessInfoHandleBackend::create_singleton);
+    BACKEND_CONSTRUCTORS
+        .write()
+        .insert(9, TimerHandleBackend::create_singleton);
 }
 
 pub async fn open(
@@ -75,3 +101,33 @@ pub async fn open(
     backend.open(fd_id, options).await?;
     Ok(backend)
 }
+
+/// Opens the backend `backend_id` and returns a handle to it with the given fd_id
+pub async fn open_handle(
+    backend_id: &usize,
+    fd_id: usize,
+    options: &[usize],
+) -> Result<Handle, EncodedError> {
+    let backend_meta = crate::handle::allocate_backend_id();
+    let backend_instance = open(backend_id, &backend_meta, options).await?;
+    let handle = Handle {
+        fd_id,
+        backend: Arc::downgrade(&backend_instance),
+        backend_meta,
+    };
+    core::mem::forget(backend_instance);
+    Ok(handle)
+}
+
+/// Fills in the standard fds that are missing from `handles` with handles to the console
+pub async fn open_standard_handles(
+    handles: &mut BTreeMap<usize, Handle>,
+) -> Result<(), EncodedError> {
+    use kernel_syscall_abi::{STDERR_FD, STDIN_FD, STDOUT_FD};
+    for fd in [STDIN_FD, STDOUT_FD, STDERR_FD] {
+        if !handles.contains_key(&fd) {
+            handles.insert(fd, open_handle(&6, fd, &[]).await?);
+        }
+    }
+    Ok(())
+}
@@ -3,28 +3,35 @@
 use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc};
 
 use flat_bytes::Flat;
-use kernel_as_register::EncodedError;
-use kernel_cpu::csr::SATP_SV39;
+use kernel_as_register::{AsRegister, EncodedError};
+use kernel_syscall_abi::{process_egg::ProcessEggError, DuplicateError};
 
+use super::call_as_register_function;
 use crate::{
-    handle::HandleBackend,
-    lock::future::rwlock::RwLock,
+    handle::{Handle, HandleBackend},
+    lock::{future::rwlock::RwLock, shared::RwLock as SharedRwLock},
     paging::{
+        address_space::AddressSpace,
+        sv39::is_user_range,
         EntryBits::{RWX, USER, VALID},
         Paging,
     },
-    process::{new_process, Process},
-    virtual_buffers,
+    process::{new_process, ExitStatus, Process},
 };
 
 pub struct ProcessEgg {
     name: String,
     start_address: usize,
-    root_table: Box<crate::paging::Table>,
+    address_space: AddressSpace,
+    // Handles that the process will start with
+    handles: BTreeMap<usize, Handle>,
 }
 
 pub struct ProcessEggBackend {
     handle_eggs: RwLock<BTreeMap<usize, RwLock<ProcessEgg>>>,
+    // Eggs that have already hatched, so that the parent can wait for the child to exit
+    // (PID, exit status)
+    hatched: SharedRwLock<BTreeMap<usize, (usize, Arc<ExitStatus>)>>,
 }
 
 #[derive(Flat)]
@@ -44,22 +51,18 @@ impl<'this> HandleBackend for ProcessEggBackend {
     {
         Arc::new(Self {
             handle_eggs: RwLock::new(BTreeMap::new()),
+            hatched: SharedRwLock::new(BTreeMap::new()),
         })
     }
 
     async fn open(&self, fd_id: &usize, _options: &[usize]) -> Result<usize, EncodedError> {
-        let mut egg = ProcessEgg {
-            root_table: Box::new(crate::paging::Table::zeroed()),
+        let egg = ProcessEgg {
+            address_space: AddressSpace::new(),
             start_address: 0,
             name: String::new(),
+            handles: BTreeMap::new(),
         };
 
-        let mut root_table = crate::paging::sv39::RootTable(&mut egg.root_table);
-
-        virtual_buffers::initialize_root_table(&mut root_table);
-
-        root_table.map(0x80000000, 0x80000000, 0x80000000, VALID | RWX);
-
         self.handle_eggs
             .write()
             .await
@@ -72,13 +75,54 @@ impl<'this> HandleBackend for ProcessEggBackend {
         "ProcessEggBackend"
     }
 
+    async fn receive_handle(
+        &self,
+        fd_id: &usize,
+        slot: usize,
+        mut handle: Handle,
+    ) -> Result<usize, EncodedError> {
+        call_as_register_function::<DuplicateError, _, _, _>(async move || {
+            let btreemap_lock = self.handle_eggs.read().await;
+            let egg = match btreemap_lock.get(fd_id) {
+                Some(egg) => egg,
+                None => {
+                    let _ = handle.close(&[]);
+                    return Err(DuplicateError::NoSuchHandle);
+                }
+            };
+            let mut egg = egg.write().await;
+            let slot = if slot == usize::MAX {
+                crate::handle::next_fd_number(&egg.handles)
+            } else if egg.handles.contains_key(&slot) {
+                let _ = handle.close(&[]);
+                return Err(DuplicateError::SlotTaken);
+            } else {
+                slot
+            };
+            handle.fd_id = slot;
+            egg.handles.insert(slot, handle);
+            Ok(slot)
+        })
+        .await
+    }
+
+    /// Waits for the hatched process to exit and returns its exit code
     async fn read(
         &self,
-        _fd_id: &usize,
+        fd_id: &usize,
         _buf: &mut [u8],
         _options: &[usize],
     ) -> Result<usize, EncodedError> {
-        Ok(0)
+        call_as_register_function::<ProcessEggError, _, _, _>(async move || {
+            let exit_status = self
+                .hatched
+                .read()
+                .get(fd_id)
+                .map(|(_, exit_status)| exit_status.clone())
+                .ok_or(ProcessEggError::NotHatched)?;
+            Ok(exit_status.wait().await)
+        })
+        .await
     }
     async fn write(
         &self,
@@ -93,30 +137,38 @@ impl<'this> HandleBackend for ProcessEggBackend {
         use ProcessEggPacketHeader::*;
         match header {
             Memory(address) => {
+                if !is_user_range(address, data.len()) {
+                    return Err(ProcessEggError::InvalidAddress.as_register());
+                }
                 let page_offset = address % 4096;
                 let page_aligned_address = address - page_offset;
                 println!("offset {:?}", page_offset);
-                let mut table = crate::paging::sv39::RootTable(&mut *egg.root_table);
+                let mut table = egg.address_space.root_table();
                 let iter1 =
                     (page_aligned_address..(page_aligned_address + data.len())).step_by(4096);
                 let iter2 = iter1.clone();
-                let mut get_slice_for_page = |page_number| -> &'static mut [u8] {
-                    if let Ok(physical_address) = unsafe { table.query_physical_address(page_number) } {
-                        unsafe { core::slice::from_raw_parts_mut(physical_address as *mut _, 4096) }
-                    } else {
-                        let mut slice = kernel_util::boxed_slice_with_alignment(4096, 4096, &0u8);
-                        let addr = &mut slice[0] as *mut u8;
-                        table.map(addr as usize, page_number, 0x4096, RWX | VALID | USER);
-                        core::mem::forget(slice);
-                        unsafe { core::slice::from_raw_parts_mut(addr, 4096) }
-                    }
-                };
+                let mut get_slice_for_page =
+                    |page_number| -> Result<&'static mut [u8], EncodedError> {
+                        if let Ok(physical_address) =
+                            unsafe { table.query_physical_address(page_number) }
+                        {
+                            Ok(unsafe {
+                                core::slice::from_raw_parts_mut(physical_address as *mut _, 4096)
+                            })
+                        } else {
+                            // Pages mapped until now are freed along with the egg
+                            let addr = crate::frame_allocator::allocate_zeroed(0)
+                                .ok_or(ProcessEggError::OutOfMemory.as_register())?;
+                            table.map(addr, page_number, 4096, RWX | VALID | USER);
+                            Ok(unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, 4096) })
+                        }
+                    };
                 for (page_number, next_page_number) in iter1.zip(iter2) {
                     let index_in_data = page_number - page_aligned_address;
                     println!("pg {:x}", page_number);
                     assert!(page_number < 0x80000000);
-                    let this_page = get_slice_for_page(page_number);
-                    let next_page = get_slice_for_page(next_page_number);
+                    let this_page = get_slice_for_page(page_number)?;
+                    let next_page = get_slice_for_page(next_page_number)?;
                     this_page[page_offset..4096.min(data.len() + page_offset)].copy_from_slice(
                         &data[index_in_data..(index_in_data + 4096 - page_offset).min(data.len())],
                     );
@@ -135,28 +187,59 @@ impl<'this> HandleBackend for ProcessEggBackend {
                 drop(egg);
                 drop(btreemap_lock);
 
-                let egg = self
+                let mut egg = self
                     .handle_eggs
                     .write()
                     .await
                     .remove(fd_id)
                     .unwrap()
                     .into_inner();
-                new_process(move |process: &mut Process| {
+                if let Err(e) = super::open_standard_handles(&mut egg.handles).await {
+                    // The egg can't hatch anymore, so close its handles like discarding it would
+                    for (_, handle) in egg.handles {
+                        let _ = handle.close(&[]);
+                    }
+                    return Err(e);
+                }
+                let exit_status = Arc::new(ExitStatus::default());
+                let child_exit_status = exit_status.clone();
+                let pid = new_process(move |process: &mut Process| {
                     let process: &mut Process = process;
                     process.name = Some(egg.name);
-                    println!("{:?}", unsafe {
-                        egg.root_table[0].as_table()[0].as_table()[0x10]
-                    });
-                    let addr = &(*egg.root_table) as *const _;
-                    println!("Addr {:?}", addr);
-                    core::mem::forget(egg.root_table);
+                    process.handles = egg.handles;
+                    process.exit_status = child_exit_status;
                     process.trap_frame.pc = egg.start_address;
-                    process.trap_frame.satp = (addr as usize >> 12) | SATP_SV39;
+                    process.address_space = Some(egg.address_space);
                 });
+                self.hatched.write().insert(*fd_id, (pid, exit_status));
+                // Hatching returns the PID of the new process
+                return Ok(pid);
             }
             _ => panic!(),
         }
         Ok(buf.len())
     }
+
+    fn process_id(&self, fd_id: &usize) -> Option<usize> {
+        self.hatched.read().get(fd_id).map(|(pid, _)| *pid)
+    }
+
+    fn close(&self, fd_id: &usize, _options: &[usize]) -> Result<(), EncodedError> {
+        self.hatched.write().remove(fd_id);
+        // Eggs that never hatched still own their address space. The lock is only held
+        // for short sections that don't wait on anything else, so spinning is fine
+        let egg = loop {
+            if let Some(mut eggs) = self.handle_eggs.try_write() {
+                break eggs.remove(fd_id);
+            }
+            core::hint::spin_loop();
+        };
+        // The handles it was given would otherwise never reach their backends' close
+        if let Some(egg) = egg {
+            for (_, handle) in egg.into_inner().handles {
+                let _ = handle.close(&[]);
+            }
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,193 @@
+//! Read-only handle backend that describes running processes (like /proc) and harts, for tools like ps and top
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+use core::sync::atomic::Ordering;
+
+use flat_bytes::Flat;
+use kernel_as_register::EncodedError;
+use kernel_syscall_abi::process_info::{
+    HandleInfoHeader, HartInfoHeader, ProcessInfoError, ProcessInfoHeader, ProcessInfoState,
+    ALL_PROCESSES, HARTS,
+};
+
+use super::call_as_register_function;
+use crate::{
+    handle::HandleBackend,
+    hart::HART_META,
+    lock::shared::RwLock,
+    process::{Process, ProcessState, PROCESSES},
+    timeout::get_time,
+};
+
+struct ProcessInfoReader {
+    // Only this PID, ALL_PROCESSES or HARTS
+    pid: usize,
+    // Processes (or harts) with a lower ID have already been read
+    next_pid: usize,
+}
+
+#[derive(Default)]
+pub struct ProcessInfoHandleBackend {
+    readers: RwLock<BTreeMap<usize, ProcessInfoReader>>,
+}
+
+fn serialize_process(pid: usize, process: &Process) -> Vec<u8> {
+    let name = process.name.as_deref().unwrap_or("");
+    let state = match process.state {
+        ProcessState::Running => ProcessInfoState::Running,
+        ProcessState::Yielded => ProcessInfoState::Yielded,
+        ProcessState::Pending => ProcessInfoState::Pending,
+        ProcessState::Scheduled => ProcessInfoState::Scheduled,
+    };
+    let mut data = ProcessInfoHeader {
+        pid,
+        user_id: process.user_id as usize,
+        supervisor: process.is_supervisor as usize,
+        state: state as usize,
+        memory_usage: process.memory_usage(),
+        user_time: process.user_time.load(Ordering::Relaxed) as usize,
+        kernel_time: process.kernel_time.load(Ordering::Relaxed) as usize,
+        nice: process.nice as usize,
+        allowed_harts: process.allowed_harts,
+        name_length: name.len(),
+        handle_count: process.handles.len(),
+    }
+    .serialize();
+    data.extend_from_slice(name.as_bytes());
+    for (fd, handle) in process.handles.iter() {
+        let backend_name = handle
+            .backend
+            .upgrade()
+            .map(|backend| backend.name())
+            .unwrap_or("");
+        data.extend_from_slice(
+            &HandleInfoHeader {
+                fd: *fd,
+                backend_name_length: backend_name.len(),
+            }
+            .serialize(),
+        );
+        data.extend_from_slice(backend_name.as_bytes());
+    }
+    data
+}
+
+/// Hart ID and record of each hart starting at `first_hart_id`
+fn serialize_harts(first_hart_id: usize) -> Vec<(usize, Vec<u8>)> {
+    let time = get_time() as usize;
+    HART_META
+        .read()
+        .range(first_hart_id..)
+        .map(|(hart_id, meta)| {
+            let record = HartInfoHeader {
+                hart_id: *hart_id,
+                idle_time: meta.cpu_time.lock().idle_time as usize,
+                time,
+            }
+            .serialize();
+            (*hart_id, record)
+        })
+        .collect()
+}
+
+#[async_trait]
+impl HandleBackend for ProcessInfoHandleBackend {
+    fn create_singleton() -> Arc<dyn HandleBackend + Send + Sync + 'static>
+    where
+        Self: Sized,
+    {
+        Arc::new(Self::default())
+    }
+
+    async fn open(&self, id: &usize, options: &[usize]) -> Result<usize, EncodedError> {
+        call_as_register_function::<ProcessInfoError, _, _, _>(async move || {
+            // a1 (Option #0) = PID, or ALL_PROCESSES
+            let pid = options[0];
+            if pid != ALL_PROCESSES
+                && pid != HARTS
+                && !PROCESSES
+                    .read()
+                    .get(&pid)
+                    .map(|slot| slot.is_used())
+                    .unwrap_or(false)
+            {
+                return Err(ProcessInfoError::NoSuchProcess);
+            }
+            self.readers.write().insert(
+                *id,
+                ProcessInfoReader {
+                    pid,
+                    next_pid: if pid == ALL_PROCESSES || pid == HARTS {
+                        0
+                    } else {
+                        pid
+                    },
+                },
+            );
+            Ok(0)
+        })
+        .await
+    }
+
+    fn name(&self) -> &'static str {
+        "ProcessInfoHandleBackend"
+    }
+
+    async fn read(
+        &self,
+        id: &usize,
+        buf: &mut [u8],
+        _options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        call_as_register_function::<ProcessInfoError, _, _, _>(async move || {
+            let (pid, next_pid) = {
+                let readers = self.readers.read();
+                let reader = &readers[id];
+                (reader.pid, reader.next_pid)
+            };
+            let records: Vec<(usize, Vec<u8>)> = if pid == HARTS {
+                serialize_harts(next_pid)
+            } else {
+                // Don't hold the PROCESSES lock while locking each process
+                let processes: Vec<_> = PROCESSES
+                    .read()
+                    .range(next_pid..)
+                    .filter(|(this_pid, _)| pid == ALL_PROCESSES || **this_pid == pid)
+                    .filter_map(|(this_pid, slot)| Some((*this_pid, slot.unwrap_ref()?.clone())))
+                    .collect();
+                processes
+                    .into_iter()
+                    .map(|(this_pid, process)| {
+                        (this_pid, serialize_process(this_pid, &process.read()))
+                    })
+                    .collect()
+            };
+
+            let mut written = 0;
+            let mut next_pid = next_pid;
+            for (this_pid, data) in records {
+                if written + data.len() > buf.len() {
+                    if written == 0 {
+                        return Err(ProcessInfoError::BufferTooSmall);
+                    }
+                    break;
+                }
+                buf[written..written + data.len()].copy_from_slice(&data);
+                written += data.len();
+                next_pid = this_pid + 1;
+            }
+            if written == 0 {
+                // Everything has been read
+                next_pid = usize::MAX;
+            }
+            self.readers.write().get_mut(id).unwrap().next_pid = next_pid;
+            Ok(written)
+        })
+        .await
+    }
+
+    fn close(&self, id: &usize, _options: &[usize]) -> Result<(), EncodedError> {
+        self.readers.write().remove(id);
+        Ok(())
+    }
+}
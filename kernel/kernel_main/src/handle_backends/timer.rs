@@ -0,0 +1,120 @@
+//! Handle backend for timers that fire once or periodically. Reading blocks until the timer fires
+
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
+use core::time::Duration;
+
+use kernel_as_register::EncodedError;
+use kernel_syscall_abi::time::TimerError;
+
+use super::call_as_register_function;
+use crate::{
+    handle::HandleBackend,
+    lock::shared::RwLock,
+    time::ticks_from_duration,
+    timeout::{get_time, TimeoutFuture},
+};
+
+#[derive(Clone, Copy)]
+struct Timer {
+    // When it fires next, or None if it won't fire anymore
+    next: Option<u64>,
+    // In ticks. 0 for timers that only fire once
+    period: u64,
+}
+
+impl Timer {
+    // Options are in nanoseconds
+    fn from_options(options: &[usize]) -> Self {
+        Timer {
+            next: Some(get_time() + ticks_from_duration(Duration::from_nanos(options[0] as u64))),
+            period: ticks_from_duration(Duration::from_nanos(options[1] as u64)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TimerHandleBackend {
+    timers: RwLock<BTreeMap<usize, Timer>>,
+}
+
+#[async_trait]
+impl HandleBackend for TimerHandleBackend {
+    fn create_singleton() -> Arc<dyn HandleBackend + Send + Sync + 'static>
+    where
+        Self: Sized,
+    {
+        Arc::new(Self::default())
+    }
+
+    async fn open(&self, id: &usize, options: &[usize]) -> Result<usize, EncodedError> {
+        // a1 (Option #0) = nanoseconds until it fires
+        // a2 (Option #1) = period in nanoseconds, or 0
+        self.timers
+            .write()
+            .insert(*id, Timer::from_options(options));
+        Ok(0)
+    }
+
+    fn name(&self) -> &'static str {
+        "TimerHandleBackend"
+    }
+
+    /// Returns how many times the timer fired since the last read
+    async fn read(
+        &self,
+        id: &usize,
+        _buf: &mut [u8],
+        _options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        call_as_register_function::<TimerError, _, _, _>(async move || loop {
+            let timer = *self
+                .timers
+                .read()
+                .get(id)
+                .ok_or(TimerError::InvalidHandle)?;
+            let next = match timer.next {
+                Some(next) => next,
+                None => return Ok(0),
+            };
+            let now = get_time();
+            if now < next {
+                TimeoutFuture::absolute(next).await;
+                continue;
+            }
+            let (fired, next) = if timer.period == 0 {
+                (1, None)
+            } else {
+                let fired = 1 + (now - next) / timer.period;
+                (fired, Some(next + fired * timer.period))
+            };
+            // The timer might have been re-armed while we were waiting
+            let mut timers = self.timers.write();
+            let current = timers.get_mut(id).ok_or(TimerError::InvalidHandle)?;
+            if current.next == timer.next {
+                current.next = next;
+                return Ok(fired as usize);
+            }
+        })
+        .await
+    }
+
+    /// Re-arms the timer
+    async fn write(
+        &self,
+        id: &usize,
+        _buf: &[u8],
+        options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        // a3 (Option #0) = nanoseconds until it fires
+        // a4 (Option #1) = period in nanoseconds, or 0
+        self.timers
+            .write()
+            .insert(*id, Timer::from_options(options));
+        Ok(0)
+    }
+
+    fn close(&self, id: &usize, _options: &[usize]) -> Result<(), EncodedError> {
+        self.timers.write().remove(id);
+        Ok(())
+    }
+}
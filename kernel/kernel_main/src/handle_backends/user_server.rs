@@ -0,0 +1,418 @@
+//! Handle backend that forwards handle operations to a userspace process acting as a server
+//!
+//! A process opens this backend in `Serve` mode to register itself as the server for a name.
+//! Other processes open it in `Connect` mode with the same name, and their open, read, write and close calls
+//! are turned into request messages that the server reads from its server handle.
+//! The server completes each request by writing a reply, which wakes up the blocked caller.
+
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use flat_bytes::Flat;
+use kernel_as_register::EncodedError;
+use kernel_syscall_abi::user_server::{
+    UserServerError, UserServerOpenMode, UserServerReplyHeader, UserServerRequestHeader,
+};
+
+use super::call_as_register_function;
+use crate::{
+    handle::HandleBackend,
+    lock::shared::{Mutex, RwLock},
+};
+
+struct Reply {
+    result: usize,
+    error: usize,
+    data: Vec<u8>,
+}
+
+enum PendingReply {
+    Waiting(Option<Waker>),
+    Done(Reply),
+}
+
+pub struct UserServer {
+    name: String,
+    is_alive: AtomicBool,
+    // Serialized requests waiting for the server to read them, and the ID of the reply they expect
+    requests: Mutex<VecDeque<(Option<usize>, Vec<u8>)>>,
+    request_wakers: Mutex<Vec<Waker>>,
+    replies: Mutex<BTreeMap<usize, PendingReply>>,
+}
+
+impl UserServer {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            is_alive: AtomicBool::new(true),
+            requests: Mutex::new(VecDeque::new()),
+            request_wakers: Mutex::new(Vec::new()),
+            replies: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn push_request(&self, request_id: Option<usize>, request: Vec<u8>) {
+        self.requests.lock().push_back((request_id, request));
+        for waker in core::mem::take(&mut *self.request_wakers.lock()) {
+            waker.wake();
+        }
+    }
+
+    /// Queues a request that expects a reply and returns its request ID
+    fn send_request(
+        &self,
+        header: impl FnOnce(usize) -> UserServerRequestHeader,
+        data: &[u8],
+    ) -> usize {
+        let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+        self.replies
+            .lock()
+            .insert(request_id, PendingReply::Waiting(None));
+        let mut request = header(request_id).serialize();
+        request.extend_from_slice(data);
+        self.push_request(Some(request_id), request);
+        request_id
+    }
+
+    fn complete(&self, request_id: usize, reply: Reply) -> Result<(), UserServerError> {
+        let mut replies = self.replies.lock();
+        match replies.get_mut(&request_id) {
+            Some(pending @ PendingReply::Waiting(_)) => {
+                if let PendingReply::Waiting(Some(waker)) =
+                    core::mem::replace(pending, PendingReply::Done(reply))
+                {
+                    waker.wake();
+                }
+                Ok(())
+            }
+            _ => Err(UserServerError::InvalidMessage),
+        }
+    }
+
+    /// Makes all callers that are waiting on this server fail
+    fn shut_down(&self) {
+        self.is_alive.store(false, Ordering::Release);
+        self.requests.lock().clear();
+        for pending in self.replies.lock().values_mut() {
+            if let PendingReply::Waiting(waker) = pending {
+                if let Some(waker) = waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+        for waker in core::mem::take(&mut *self.request_wakers.lock()) {
+            waker.wake();
+        }
+    }
+
+    fn next_request(&self) -> NextRequestFuture<'_> {
+        NextRequestFuture { server: self }
+    }
+
+    fn reply(&self, request_id: usize) -> ReplyFuture<'_> {
+        ReplyFuture {
+            server: self,
+            request_id,
+        }
+    }
+
+    /// Sends a request and blocks until the server replies to it
+    async fn call(
+        &self,
+        header: impl FnOnce(usize) -> UserServerRequestHeader,
+        data: &[u8],
+    ) -> Result<Reply, UserServerError> {
+        if !self.is_alive.load(Ordering::Acquire) {
+            return Err(UserServerError::ServerGone);
+        }
+        let reply = self.reply(self.send_request(header, data)).await?;
+        if reply.error != 0 {
+            Err(UserServerError::RequestFailed(reply.error))
+        } else {
+            Ok(reply)
+        }
+    }
+}
+
+struct NextRequestFuture<'server> {
+    server: &'server UserServer,
+}
+
+impl<'server> Future for NextRequestFuture<'server> {
+    type Output = Result<(), UserServerError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if !self.server.is_alive.load(Ordering::Acquire) {
+            Poll::Ready(Err(UserServerError::ServerGone))
+        } else if !self.server.requests.lock().is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            // The same server might poll again before a request arrives
+            let mut wakers = self.server.request_wakers.lock();
+            if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+                wakers.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+
+struct ReplyFuture<'server> {
+    server: &'server UserServer,
+    request_id: usize,
+}
+
+impl<'server> Future for ReplyFuture<'server> {
+    type Output = Result<Reply, UserServerError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut replies = self.server.replies.lock();
+        match replies.remove(&self.request_id) {
+            Some(PendingReply::Done(reply)) => Poll::Ready(Ok(reply)),
+            _ if !self.server.is_alive.load(Ordering::Acquire) => {
+                Poll::Ready(Err(UserServerError::ServerGone))
+            }
+            _ => {
+                replies.insert(
+                    self.request_id,
+                    PendingReply::Waiting(Some(cx.waker().clone())),
+                );
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'server> Drop for ReplyFuture<'server> {
+    fn drop(&mut self) {
+        // If the caller is gone (its syscall was canceled or it was killed), nobody takes the
+        // reply, and the server doesn't have to handle the request if it hasn't read it yet
+        self.server.replies.lock().remove(&self.request_id);
+        self.server
+            .requests
+            .lock()
+            .retain(|(request_id, _)| *request_id != Some(self.request_id));
+    }
+}
+
+enum UserServerHandle {
+    Server(Arc<UserServer>),
+    Client {
+        server: Arc<UserServer>,
+        client_id: usize,
+    },
+}
+
+static NEXT_REQUEST_ID: AtomicUsize = AtomicUsize::new(1);
+
+pub struct UserServerBackend {
+    servers: RwLock<BTreeMap<String, Arc<UserServer>>>,
+    handles: RwLock<BTreeMap<usize, UserServerHandle>>,
+}
+
+impl UserServerBackend {
+    fn get_handle(&self, id: &usize) -> Option<(Arc<UserServer>, Option<usize>)> {
+        self.handles.read().get(id).map(|handle| match handle {
+            UserServerHandle::Server(server) => (server.clone(), None),
+            UserServerHandle::Client { server, client_id } => (server.clone(), Some(*client_id)),
+        })
+    }
+}
+
+#[async_trait]
+impl HandleBackend for UserServerBackend {
+    fn create_singleton() -> alloc::sync::Arc<dyn HandleBackend + Send + Sync + 'static>
+    where
+        Self: Sized,
+    {
+        Arc::new(Self {
+            servers: RwLock::new(BTreeMap::new()),
+            handles: RwLock::new(BTreeMap::new()),
+        })
+    }
+
+    async fn open(&self, id: &usize, options: &[usize]) -> Result<usize, EncodedError> {
+        call_as_register_function::<UserServerError, _, _, _>(async move || {
+            // a1 (Option #0) = start of the server name
+            // a2 (Option #1) = length of the server name
+            // a3 (Option #2) = UserServerOpenMode
+            // The rest of the options are passed to the server in the Open request
+            let name = unsafe { core::slice::from_raw_parts(options[0] as *const u8, options[1]) };
+            let name = String::from(
+                core::str::from_utf8(name).map_err(|_| UserServerError::InvalidMessage)?,
+            );
+
+            if options[2] == UserServerOpenMode::Serve as usize {
+                let server = {
+                    let mut servers = self.servers.write();
+                    if servers
+                        .get(&name)
+                        .map(|s| s.is_alive.load(Ordering::Acquire))
+                        .unwrap_or(false)
+                    {
+                        return Err(UserServerError::NameTaken);
+                    }
+                    let server = Arc::new(UserServer::new(name.clone()));
+                    servers.insert(name, server.clone());
+                    server
+                };
+                self.handles
+                    .write()
+                    .insert(*id, UserServerHandle::Server(server));
+            } else {
+                let server = self
+                    .servers
+                    .read()
+                    .get(&name)
+                    .cloned()
+                    .ok_or(UserServerError::NoSuchServer)?;
+                let mut forwarded_options = [0; 3];
+                forwarded_options.copy_from_slice(&options[3..6]);
+                server
+                    .call(
+                        |request_id| UserServerRequestHeader::Open {
+                            request_id,
+                            client_id: *id,
+                            options: forwarded_options,
+                        },
+                        &[],
+                    )
+                    .await?;
+                self.handles.write().insert(
+                    *id,
+                    UserServerHandle::Client {
+                        server,
+                        client_id: *id,
+                    },
+                );
+            }
+            Ok(0)
+        })
+        .await
+    }
+
+    fn name(&self) -> &'static str {
+        "UserServerBackend"
+    }
+
+    async fn read(
+        &self,
+        id: &usize,
+        buf: &mut [u8],
+        _options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        call_as_register_function::<UserServerError, _, _, _>(async move || {
+            let (server, client_id) = self.get_handle(id).ok_or(UserServerError::InvalidMessage)?;
+            match client_id {
+                None => {
+                    // The server wants the next request
+                    server.next_request().await?;
+                    let mut requests = server.requests.lock();
+                    let (_, request) = requests.front().ok_or(UserServerError::InvalidMessage)?;
+                    if request.len() > buf.len() {
+                        // Leave the request in the queue so that the server can retry with a larger buffer
+                        return Err(UserServerError::BufferTooSmall);
+                    }
+                    buf[..request.len()].copy_from_slice(request);
+                    Ok(requests.pop_front().unwrap().1.len())
+                }
+                Some(client_id) => {
+                    let length = buf.len();
+                    let reply = server
+                        .call(
+                            |request_id| UserServerRequestHeader::Read {
+                                request_id,
+                                client_id,
+                                length,
+                            },
+                            &[],
+                        )
+                        .await?;
+                    let copied = reply.data.len().min(buf.len());
+                    buf[..copied].copy_from_slice(&reply.data[..copied]);
+                    // The server can't claim to have sent more than the client received
+                    Ok(reply.result.min(copied))
+                }
+            }
+        })
+        .await
+    }
+
+    async fn write(
+        &self,
+        id: &usize,
+        buf: &[u8],
+        _options: &[usize],
+    ) -> Result<usize, EncodedError> {
+        call_as_register_function::<UserServerError, _, _, _>(async move || {
+            let (server, client_id) = self.get_handle(id).ok_or(UserServerError::InvalidMessage)?;
+            match client_id {
+                None => {
+                    // The server is replying to a request
+                    let (header, size) = UserServerReplyHeader::deserialize_with_size(buf)
+                        .ok_or(UserServerError::InvalidMessage)?;
+                    server.complete(
+                        header.request_id,
+                        Reply {
+                            result: header.result,
+                            error: header.error,
+                            data: Vec::from(&buf[size..]),
+                        },
+                    )?;
+                    Ok(buf.len())
+                }
+                Some(client_id) => {
+                    let reply = server
+                        .call(
+                            |request_id| UserServerRequestHeader::Write {
+                                request_id,
+                                client_id,
+                            },
+                            buf,
+                        )
+                        .await?;
+                    Ok(reply.result.min(buf.len()))
+                }
+            }
+        })
+        .await
+    }
+
+    fn close(&self, id: &usize, _options: &[usize]) -> Result<(), EncodedError> {
+        match self.handles.write().remove(id) {
+            Some(UserServerHandle::Server(server)) => {
+                let mut servers = self.servers.write();
+                if servers
+                    .get(&server.name)
+                    .map(|s| Arc::ptr_eq(s, &server))
+                    .unwrap_or(false)
+                {
+                    servers.remove(&server.name);
+                }
+                drop(servers);
+                server.shut_down();
+            }
+            Some(UserServerHandle::Client { server, client_id }) => {
+                if server.is_alive.load(Ordering::Acquire) {
+                    server.push_request(
+                        None,
+                        UserServerRequestHeader::Close { client_id }.serialize(),
+                    );
+                }
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
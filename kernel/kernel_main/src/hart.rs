@@ -1,24 +1,37 @@
-/// Start and setup new harts
+/// Start and setup new harts, and take them offline
 use alloc::{boxed::Box, collections::BTreeMap, sync::Arc};
 use core::{
     arch::asm,
     pin::Pin,
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
 };
 
 use aligned::{Aligned, A16};
+use kernel_syscall_abi::HartError;
 
 use crate::{
-    cpu::{self, load_hartid},
-    lock::shared::RwLock,
+    cpu::{self, load_hartid, read_sscratch, BOOT_HART},
+    cpu_time::HartCpuTime,
+    kernel_stack::{KernelStack, StackOwner},
+    lock::shared::{Mutex, RwLock},
+    paging::address_space::kernel_satp,
     plic::Plic0,
-    process::{self, try_get_process, TASK_STACK_SIZE},
+    process::{self, try_get_process, weak_get_process, PidSlot, PROCESSES, TASK_STACK_SIZE},
     s_trap_vector, sbi,
-    scheduler::schedule_next_slice,
-    timer_queue,
+    scheduler::{self, schedule_next_slice, RunQueue},
+    time::{ticks_from_duration, Instant},
+    timer_queue::{self, TimerEvent, TimerEventCause},
     trap::TrapFrame,
+    trap_frame::TrapFrameExt,
 };
 
+/// How long a hart has to be idle before it suspends instead of just waiting for interrupts
+const SUSPEND_AFTER: Duration = Duration::from_secs(1);
+
+/// Where harts start executing, saved by start_all_harts to bring harts back online
+static HART_START_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
 // Data associated with a hart
 pub struct HartMeta {
     pub plic: Plic0,
@@ -26,6 +39,20 @@ pub struct HartMeta {
     pub boot_frame: RwLock<Pin<Box<TrapFrame>>>,
     pub is_panicking: AtomicBool,
     pub idle_process: AtomicUsize,
+    pub cpu_time: Mutex<HartCpuTime>,
+    /// Processes that are ready to run on this hart
+    pub run_queue: Mutex<RunQueue>,
+    /// Whether the hart has nothing to run. Idle harts don't get timer ticks,
+    /// so other harts send them an IPI when they have work for them
+    pub is_idle: AtomicBool,
+    /// Whether an IPI has been sent to this hart and it hasn't handled it yet
+    pub wakeup_pending: AtomicBool,
+    /// When the hart started being idle, or u64::MAX if it's running processes
+    pub idle_since: AtomicU64,
+    /// The hart goes offline when it handles the next IPI
+    pub stop_requested: AtomicBool,
+    /// The satp of the process the hart is running, used to know which harts need a TLB shootdown
+    pub active_satp: AtomicUsize,
 }
 
 impl HartMeta {
@@ -40,6 +67,22 @@ impl HartMeta {
     }
 }
 
+/// Sends an IPI to a hart so that it leaves its idle process and looks for something to run
+pub fn wake_hart(hart_id: usize) {
+    let meta = match get_hart_meta(hart_id) {
+        Some(meta) => meta,
+        None => return,
+    };
+    // One IPI is enough until the hart handles it
+    if meta.wakeup_pending.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    if let Err(e) = sbi::send_ipi(sbi::HartMask::single(hart_id)) {
+        warn!("Couldn't send an IPI to hart {}: {:?}", hart_id, e);
+        meta.wakeup_pending.store(false, Ordering::Release);
+    }
+}
+
 pub static HART_META: RwLock<BTreeMap<usize, Arc<HartMeta>>> = RwLock::new(BTreeMap::new());
 
 pub fn get_hart_meta(hartid: usize) -> Option<Arc<HartMeta>> {
@@ -56,6 +99,13 @@ pub unsafe fn add_boot_hart(trap_frame: TrapFrame) {
         boot_frame: RwLock::new(Pin::new(Box::new(trap_frame))),
         is_panicking: AtomicBool::new(false),
         idle_process: AtomicUsize::new(0),
+        cpu_time: Mutex::new(HartCpuTime::new()),
+        run_queue: Mutex::new(RunQueue::new()),
+        is_idle: AtomicBool::new(false),
+        wakeup_pending: AtomicBool::new(false),
+        idle_since: AtomicU64::new(u64::MAX),
+        stop_requested: AtomicBool::new(false),
+        active_satp: AtomicUsize::new(0),
     };
     HART_META.write().insert(load_hartid(), Arc::new(meta));
 }
@@ -69,6 +119,8 @@ pub fn add_this_secondary_hart(hartid: usize, interrupt_sp: usize) {
     trap_frame.pid = 0;
     trap_frame.hartid = hartid;
     trap_frame.interrupt_stack = interrupt_sp;
+    trap_frame.use_current_satp_as_kernel_satp();
+    trap_frame.satp = trap_frame.kernel_satp;
 
     // SAFETY: trap_frame is a valid trap frame and will live as long as this hart exists
     // so sscratch will be valid and this will not invoke UB
@@ -85,6 +137,13 @@ pub fn add_this_secondary_hart(hartid: usize, interrupt_sp: usize) {
             boot_frame: RwLock::new(trap_frame),
             is_panicking: AtomicBool::new(false),
             idle_process: AtomicUsize::new(0),
+            cpu_time: Mutex::new(HartCpuTime::new()),
+            run_queue: Mutex::new(RunQueue::new()),
+            is_idle: AtomicBool::new(false),
+            wakeup_pending: AtomicBool::new(false),
+            idle_since: AtomicU64::new(u64::MAX),
+            stop_requested: AtomicBool::new(false),
+            active_satp: AtomicUsize::new(0),
         }),
     );
 }
@@ -93,9 +152,36 @@ pub fn get_this_hart_meta() -> Option<Arc<HartMeta>> {
     get_hart_meta(load_hartid())
 }
 
+/// The interrupt stack of every hart that has been started, by hart ID. A hart gets the same stack
+/// every time it's brought online, since stopped harts don't use theirs anymore
+static HART_STACKS: Mutex<BTreeMap<usize, KernelStack>> = Mutex::new(BTreeMap::new());
+
+/// The virtual and physical addresses of the top of a hart's interrupt stack
+fn hart_stack_tops(hartid: usize) -> (usize, usize) {
+    let mut stacks = HART_STACKS.lock();
+    let stack = stacks
+        .entry(hartid)
+        .or_insert_with(|| KernelStack::new(StackOwner::Hart(hartid)));
+    (stack.top() - 0x10, stack.physical_top() - 0x10)
+}
+
+/// The interrupt stack used by processes that don't have one of their own on this hart
+pub fn interrupt_stack_top(hartid: usize) -> usize {
+    hart_stack_tops(hartid).0
+}
+
+/// # Safety
+/// start_addr must be a function that is sound and sets up harts correctly
+unsafe fn start_hart_with_stack(hartid: usize, start_addr: usize) -> Result<(), sbi::SBIError> {
+    // Harts start with paging disabled, so it gets the physical address of its stack in a1
+    let (_, physical_top) = hart_stack_tops(hartid);
+    sbi::start_hart(hartid, start_addr, physical_top)
+}
+
 /// # Safety
 /// start_addr must be a function that is sound and sets up harts correctly
 pub unsafe fn start_all_harts(start_addr: usize) {
+    HART_START_ADDRESS.store(start_addr, Ordering::Relaxed);
     for hartid in 0.. {
         match sbi::hart_get_status(hartid) {
             Err(_e) => {
@@ -104,17 +190,8 @@ pub unsafe fn start_all_harts(start_addr: usize) {
                 break;
             }
             Ok(status) => {
-                if status == 1 {
-                    // This hart is stopped
-                    // Create a stack for it and pass it in a1
-                    let process_stack = kernel_util::boxed_slice_with_alignment(4096 * 8, 4096, &0);
-                    sbi::start_hart(
-                        hartid,
-                        start_addr,
-                        process_stack.as_ptr() as usize + (4096 * 8) - 0x10,
-                    )
-                    .expect("Starting hart failed!");
-                    Box::leak(process_stack);
+                if status == sbi::HART_STOPPED {
+                    start_hart_with_stack(hartid, start_addr).expect("Starting hart failed!");
                 } else {
                     info!("hart status: {}", status)
                 }
@@ -124,8 +201,12 @@ pub unsafe fn start_all_harts(start_addr: usize) {
 }
 
 #[no_mangle]
-fn hart_entry(hartid: usize, interrupt_stack: usize) -> ! {
-    add_this_secondary_hart(hartid, interrupt_stack);
+fn hart_entry(hartid: usize, _physical_stack: usize) -> ! {
+    // The kernel is identity mapped, so this stack and code keep working.
+    // The interrupt stack is only mapped with paging enabled
+    unsafe { cpu::write_satp(kernel_satp()) };
+    cpu::fence_vma();
+    add_this_secondary_hart(hartid, interrupt_stack_top(hartid));
 
     timer_queue::init_hart();
 
@@ -152,3 +233,137 @@ fn hart_entry(hartid: usize, interrupt_stack: usize) -> ! {
         cpu::wfi()
     }
 }
+
+/// Takes a hart offline or brings it back online
+pub fn set_online(hart_id: usize, online: bool) -> Result<(), HartError> {
+    if online {
+        bring_online(hart_id)
+    } else {
+        take_offline(hart_id)
+    }
+}
+
+fn bring_online(hart_id: usize) -> Result<(), HartError> {
+    let status = sbi::hart_get_status(hart_id).map_err(|_| HartError::NoSuchHart)?;
+    if get_hart_meta(hart_id).is_some() || status != sbi::HART_STOPPED {
+        return Err(HartError::AlreadyOnline);
+    }
+    // SAFETY: This is the address that all the other harts started at
+    unsafe { start_hart_with_stack(hart_id, HART_START_ADDRESS.load(Ordering::Relaxed)) }
+        .map_err(|_| HartError::StartFailed)?;
+    info!("Hart {} is online", hart_id);
+    Ok(())
+}
+
+/// Asks a hart to go offline. It stops when it handles the IPI, see stop_this_hart
+fn take_offline(hart_id: usize) -> Result<(), HartError> {
+    sbi::hart_get_status(hart_id).map_err(|_| HartError::NoSuchHart)?;
+    if hart_id == BOOT_HART.load(Ordering::Relaxed) {
+        return Err(HartError::BootHart);
+    }
+    let meta = get_hart_meta(hart_id).ok_or(HartError::AlreadyOffline)?;
+
+    let remaining_harts = HART_META
+        .read()
+        .keys()
+        .filter(|id| **id != hart_id && **id < usize::BITS as usize)
+        .fold(0, |mask, id| mask | 1 << id);
+    let idle_process = meta.get_idle_process();
+    let pinned = PROCESSES
+        .read()
+        .iter()
+        .filter(|(pid, _)| Some(**pid) != idle_process)
+        .filter_map(|(_, slot)| slot.unwrap_ref())
+        .any(|process| process.read().allowed_harts & remaining_harts == 0);
+    if pinned {
+        return Err(HartError::ProcessesPinned);
+    }
+
+    if meta.stop_requested.swap(true, Ordering::AcqRel) {
+        return Err(HartError::AlreadyOffline);
+    }
+    wake_hart(hart_id);
+    Ok(())
+}
+
+/// Takes this hart offline: its processes and timer events move to the other harts, and then it stops.
+/// Must be called from an interrupt context
+pub fn stop_this_hart() -> ! {
+    let hart_id = load_hartid();
+    // Interrupts stay masked because sstatus.SIE is clear in the trap handler. Clearing sie
+    // doesn't guarantee that on its own, since the shared locks restore it when they're dropped
+    unsafe { cpu::write_sie(0) };
+    let current_pid = unsafe { (*read_sscratch()).pid };
+
+    let meta = get_this_hart_meta().unwrap();
+    unsafe { meta.boot_frame.write().make_current() };
+    // The idle process can only run on this hart
+    if let Some(pid) = meta.get_idle_process() {
+        meta.set_idle_process(None);
+        if PROCESSES
+            .read()
+            .get(&pid)
+            .map(PidSlot::is_used)
+            .unwrap_or(false)
+        {
+            process::delete_process(pid);
+        }
+    }
+
+    // From now on, processes don't get queued here
+    HART_META.write().remove(&hart_id);
+    if let Some(process) = weak_get_process(&current_pid).upgrade() {
+        scheduler::make_pending(&mut process.write());
+    }
+    scheduler::migrate_run_queue(&meta);
+
+    // Timeouts move to the first online hart.
+    // Time slices and suspends belong to this hart, so they're dropped
+    let other_hart = HART_META
+        .read()
+        .keys()
+        .next()
+        .copied()
+        .expect("No harts left");
+    for event in timer_queue::remove_hart(hart_id) {
+        if !matches!(
+            event.cause,
+            TimerEventCause::ContextSwitch | TimerEventCause::IdleSuspend
+        ) {
+            timer_queue::schedule_on(other_hart, event);
+        }
+    }
+    // The other hart reprograms its timer when it gets the IPI
+    wake_hart(other_hart);
+
+    info!("Hart {} is offline", hart_id);
+    drop(meta);
+    // SAFETY: This hart doesn't hold any locks, and sstatus.SIE is clear so nothing else runs on it
+    let error = unsafe { sbi::stop_hart() };
+    panic!("Couldn't stop hart {}: {:?}", hart_id, error)
+}
+
+/// Idle harts don't get timer ticks, so this wakes the hart up when it's time for it to suspend
+pub fn schedule_suspend(idle_since: u64) {
+    timer_queue::replace(TimerEvent {
+        instant: idle_since + ticks_from_duration(SUSPEND_AFTER),
+        cause: TimerEventCause::IdleSuspend,
+    });
+}
+
+/// Waits for an interrupt on an idle hart. Harts that have been idle for a while suspend instead,
+/// which uses less power but takes longer to wake up
+pub fn idle_wait(meta: &HartMeta) {
+    let idle_for = Instant::now()
+        .ticks()
+        .saturating_sub(meta.idle_since.load(Ordering::Acquire));
+    if idle_for < ticks_from_duration(SUSPEND_AFTER) {
+        cpu::wfi();
+        return;
+    }
+    // SAFETY: Retentive suspends return when an interrupt arrives, like wfi
+    if unsafe { sbi::hart_suspend(sbi::RETENTIVE_SUSPEND, 0, 0) }.is_err() {
+        // The SBI implementation doesn't support suspending
+        cpu::wfi();
+    }
+}
@@ -1,10 +1,10 @@
-//! WIP. Will include code to backtrace
+//! Backtraces of kernel code, using the unwind tables in .eh_frame
 
 use alloc::collections::BinaryHeap;
 
 use gimli::{
-    BaseAddresses, CallFrameInstruction, CfaRule, EhFrame, FrameDescriptionEntry, LittleEndian,
-    NativeEndian, UnwindContext, UnwindSection,
+    BaseAddresses, CfaRule, EhFrame, FrameDescriptionEntry, NativeEndian, UnwindContext,
+    UnwindSection,
 };
 
 use crate::{
@@ -68,125 +68,87 @@ impl<'a> From<u64> for FdeSortedByAddress<'a> {
 
 pub fn row_finder(_address: usize) {}
 
-pub fn backtrace() {
-    let mut fde_list: BinaryHeap<FdeSortedByAddress> = BinaryHeap::new();
+/// Frames deeper than this aren't printed, in case the unwind information leads to a loop
+const MAX_FRAMES: usize = 32;
 
+/// Prints a backtrace from where this function is called
+pub fn backtrace() {
     let mut frame = crate::trap::TrapFrame::zeroed();
     unsafe { store_to_trap_frame(&frame as *const TrapFrame) };
+    // store_to_trap_frame is a leaf function, so the return address is in the caller
+    frame.pc = frame.general_registers[Registers::Ra.idx()];
+    backtrace_from(&frame);
+}
+
+/// Prints the return addresses of the call stack that `frame` was running.
+/// Only kernel code has unwind information, so this stops at the first address outside of it
+pub fn backtrace_from(frame: &TrapFrame) {
+    let mut fde_list: BinaryHeap<FdeSortedByAddress> = BinaryHeap::new();
+
     let eh_start = unsafe { &__eh_frame_start as *const _ as usize };
     let eh_end = unsafe { &__eh_frame_end as *const _ as usize };
     let eh_size = eh_end - eh_start;
 
     let eh_slice = unsafe { core::slice::from_raw_parts(eh_start as *const u8, eh_size) };
-    use gimli::read::EndianSlice;
-
-    let endian_slice = EndianSlice::new(eh_slice, NativeEndian);
     let eh_section = EhFrame::new(eh_slice, NativeEndian);
     let ba = BaseAddresses::default();
     let ba = ba.set_text(unsafe { &__text_start as *const _ as usize } as u64);
     let ba = ba.set_eh_frame(eh_start as u64);
+
     let mut iter = eh_section.entries(&ba);
-    let mut g_cie = None;
-
-    while let Some(entry) = iter.next().unwrap() {
-        match entry {
-            gimli::CieOrFde::Cie(cie) => {
-                //println!("{:?}", cie);
-                let mut iter = cie.instructions(&eh_section, &ba);
-                while let Some(instruction) = iter.next().unwrap() {
-                    let instruction: CallFrameInstruction<EndianSlice<LittleEndian>> = instruction;
-                    println!("{:?}", instruction);
-                }
-                if g_cie.is_some() {
-                    panic!("{:?}", ">1 CIE!");
-                };
-                g_cie = Some(cie);
-            }
-            gimli::CieOrFde::Fde(fde) => {
-                let fde = fde
-                    .parse(|_section, _ba, _offset| {
-                        return Ok(g_cie.as_ref().unwrap().clone());
-                    })
-                    .unwrap();
-
-                /*while let Some(row) = table.next_row().unwrap() {
-                    println!("{:x} {:x}", frame.general_registers[Registers::Sp as usize], row.start_address());
-                }*/
-                fde_list.push(fde.clone().into());
+    while let Ok(Some(entry)) = iter.next() {
+        if let gimli::CieOrFde::Fde(fde) = entry {
+            if let Ok(fde) = fde.parse(|section, ba, offset| section.cie_from_offset(ba, offset)) {
+                fde_list.push(fde.into());
             }
         }
     }
-
-    // This mess seems necessary to specify the relation between the closure's lifetimes and endian_slice's lifetime.
-    use gimli::UnwindTableRow;
-    fn constrain<'c, F>(_a: &EndianSlice<'c, NativeEndian>, f: F) -> F
-    where
-        F: for<'a, 'b> Fn(
-            u64,
-            &'a mut UnwindContext<EndianSlice<'c, NativeEndian>>,
-        ) -> core::result::Result<
-            &'a UnwindTableRow<EndianSlice<'c, gimli::LittleEndian>>,
-            gimli::Error,
-        >,
-    {
-        f
-    }
-
     let fde_list = fde_list.into_sorted_vec();
-    let find_row_for_address = constrain(
-        &endian_slice,
-        |addr: u64, ctx: &mut UnwindContext<EndianSlice<NativeEndian>>| {
-            let index = fde_list
-                .binary_search(&addr.into())
-                .unwrap_or_else(|a| a - 1);
-            let fde = fde_list[index].unwrap();
-            assert!(fde.contains(addr));
-            return fde.unwind_info_for_address(&eh_section, &ba, ctx, addr);
-        },
-    );
-
-    //let mut next_addr = backtrace as *const fn() as usize as u64 + 0x10;
+
     let mut ctx = alloc::boxed::Box::new(UnwindContext::new());
-    loop {
-        let row = find_row_for_address(
-            frame.general_registers[Registers::Ra as usize] as u64,
-            &mut ctx,
-        )
-        .unwrap();
-        for &(register, ref rule) in row.registers() {
-            println!("{:?} {:?} {:?}", row.cfa(), register.0, rule);
-
-            let new_cfa = if let CfaRule::RegisterAndOffset {
-                register: cfa_register,
-                offset,
-            } = row.cfa()
-            {
-                frame.general_registers[cfa_register.0 as usize]
-                    .wrapping_add(*offset as isize as usize)
-            } else {
-                unimplemented!();
-            };
-
-            println!("{:x}", new_cfa);
-
-            // If the debug data is bad or incorrect (points to invalid memory) then this might cause UB!
-            let old_register_value = unsafe { frame.apply_gimli_rule(&(new_cfa as u64), &rule) };
-
-            frame.set_gimli_register(&register, old_register_value);
+    let mut registers = frame.clone();
+    let mut pc = frame.pc;
+    println!("Backtrace:");
+    for depth in 0..MAX_FRAMES {
+        println!("  #{} {:#x}", depth, pc);
+
+        // Return addresses point after the call, which might be past the end of the caller
+        let address = if depth == 0 { pc } else { pc - 1 } as u64;
+        let index = match fde_list.binary_search(&address.into()) {
+            Ok(index) => index,
+            Err(0) => break,
+            Err(index) => index - 1,
+        };
+        let fde = fde_list[index].unwrap();
+        if !fde.contains(address) {
+            break;
         }
-        println!(
-            "{:?} {:x}",
-            "Addr",
-            frame.general_registers[Registers::Ra as usize]
-        );
-        if frame.general_registers[Registers::Ra as usize] == 0 {
+        let row = match fde.unwind_info_for_address(&eh_section, &ba, &mut ctx, address) {
+            Ok(row) => row,
+            Err(_) => break,
+        };
+        let cfa = match row.cfa() {
+            CfaRule::RegisterAndOffset { register, offset } => registers
+                .get_gimli_register(register)
+                .wrapping_add(*offset as isize as usize),
+            _ => break,
+        };
+
+        let mut caller = registers.clone();
+        for (register, rule) in row.registers() {
+            use gimli::RegisterRule::*;
+            if let Offset(_) | ValOffset(_) | Register(_) = rule {
+                // If the debug data is bad or incorrect (points to invalid memory) then this might cause UB!
+                let value = unsafe { registers.apply_gimli_rule(&(cfa as u64), rule) };
+                caller.set_gimli_register(register, value);
+            }
+        }
+        caller.general_registers[Registers::Sp.idx()] = cfa;
+
+        pc = caller.general_registers[Registers::Ra.idx()];
+        if pc == 0 {
             break;
         }
-        drop(row);
+        registers = caller;
     }
-
-    panic!();
-
-    //let ra = frame.general_registers[Registers::Ra.idx()];
-    loop {}
 }
@@ -0,0 +1,177 @@
+//! Stacks for kernel tasks and interrupt handlers, with guard pages under them
+//!
+//! Kernel stacks live in a virtual region of their own, one stack per slot. Stacks sit at the top
+//! of their slot and the rest of it is never mapped, so running off the bottom of a stack
+//! causes a page fault instead of overwriting whatever memory is below it.
+//!
+//! The region is a single level 1 table that process page tables share with the kernel's,
+//! so stacks are mapped in every address space as soon as they're allocated.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::ops::Range;
+
+use crate::{
+    cpu::{load_hartid, read_sp},
+    frame_allocator,
+    lock::shared::Mutex,
+    paging::{new_table, sv39::RootTable, EntryBits, Paging, Table, GIGAPAGE_SIZE, ROOT_PAGE},
+    process::TASK_STACK_SIZE,
+    tlb,
+};
+
+/// The region takes up one root table entry, away from RAM and the virtual buffers
+pub const REGION_START: usize = 0x30_0000_0000;
+const REGION_ENTRY: usize = REGION_START / GIGAPAGE_SIZE;
+/// Slots are aligned to their size, which divides the megapage size, so a stack never spans
+/// two level 0 tables
+const SLOT_SIZE: usize = 0x10000;
+const SLOT_COUNT: usize = GIGAPAGE_SIZE / SLOT_SIZE;
+
+// There has to be at least one guard page
+const _: () = assert!(TASK_STACK_SIZE + crate::paging::PAGE_SIZE <= SLOT_SIZE);
+
+/// What a stack is used by, to tell who overflowed it
+#[derive(Debug, Clone, Copy)]
+pub enum StackOwner {
+    Process(usize),
+    /// The interrupt stack of a hart, for processes that don't have one of their own
+    Hart(usize),
+}
+
+struct Slots {
+    /// What uses the stack in each used slot
+    owners: BTreeMap<usize, StackOwner>,
+    /// Stacks that were dropped while a hart was still running on them: (hart ID, slot, frames)
+    deferred: Vec<(usize, usize, usize)>,
+}
+
+static SLOTS: Mutex<Slots> = Mutex::new(Slots {
+    owners: BTreeMap::new(),
+    deferred: Vec::new(),
+});
+
+fn slot_start(slot: usize) -> usize {
+    REGION_START + slot * SLOT_SIZE
+}
+
+fn stack_bottom(slot: usize) -> usize {
+    slot_start(slot) + SLOT_SIZE - TASK_STACK_SIZE
+}
+
+fn kernel_root_table() -> RootTable<'static> {
+    RootTable(unsafe { &mut *core::ptr::addr_of_mut!(ROOT_PAGE) })
+}
+
+/// Replaces the identity mapping of the region in the kernel's root table with an empty table.
+/// Must be called after frame_allocator::init and before other harts are started
+pub fn init() {
+    let table = new_table() as *mut Table as usize;
+    unsafe {
+        ROOT_PAGE[REGION_ENTRY].value = EntryBits::VALID | EntryBits::GLOBAL | table >> 2;
+    }
+    tlb::flush_local(REGION_START, GIGAPAGE_SIZE, None);
+}
+
+/// Makes the stacks accessible from a process's root table.
+/// The entry is global, so the table under it doesn't belong to the process
+pub fn share_region(table: &mut Table) {
+    table[REGION_ENTRY] = unsafe { ROOT_PAGE[REGION_ENTRY] };
+}
+
+/// Whether the range touches the region, which user processes must not map or unmap pages in
+pub fn overlaps_region(range: Range<usize>) -> bool {
+    range.start < REGION_START + GIGAPAGE_SIZE && REGION_START < range.end
+}
+
+/// What the stack that overflowed is used by, if `address` is in a guard page
+pub fn guard_page_owner(address: usize) -> Option<StackOwner> {
+    if !(REGION_START..REGION_START + GIGAPAGE_SIZE).contains(&address) {
+        return None;
+    }
+    let slot = (address - REGION_START) / SLOT_SIZE;
+    if address >= stack_bottom(slot) {
+        return None;
+    }
+    SLOTS.lock().owners.get(&slot).copied()
+}
+
+fn free(slots: &mut Slots, slot: usize, frames: usize) {
+    kernel_root_table().unmap(stack_bottom(slot), TASK_STACK_SIZE);
+    frame_allocator::release(frames);
+    slots.owners.remove(&slot);
+}
+
+/// Frees the stacks that this hart was running on when they were dropped, if it isn't anymore
+fn reap(slots: &mut Slots) {
+    let hart_id = load_hartid();
+    let sp = read_sp();
+    let (ready, waiting) = core::mem::take(&mut slots.deferred)
+        .into_iter()
+        .partition::<Vec<_>, _>(|(hart, slot, _)| {
+            *hart == hart_id && !(stack_bottom(*slot)..slot_start(*slot + 1)).contains(&sp)
+        });
+    slots.deferred = waiting;
+    for (_, slot, frames) in ready {
+        free(slots, slot, frames);
+    }
+}
+
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    /// Physical address of the frames backing the stack
+    frames: usize,
+}
+
+impl KernelStack {
+    /// Allocates a zeroed stack
+    pub fn new(owner: StackOwner) -> Self {
+        let mut slots = SLOTS.lock();
+        reap(&mut slots);
+        let slot = (0..SLOT_COUNT)
+            .find(|slot| !slots.owners.contains_key(slot))
+            .expect("Out of kernel stack slots");
+        let frames = frame_allocator::allocate_zeroed(frame_allocator::order_for(TASK_STACK_SIZE))
+            .expect("Out of memory for kernel stacks");
+        kernel_root_table().map(
+            frames,
+            stack_bottom(slot),
+            TASK_STACK_SIZE,
+            EntryBits::DATA_SUPERVISOR,
+        );
+        slots.owners.insert(slot, owner);
+        Self { slot, frames }
+    }
+
+    /// The lowest address of the stack. The page below it is a guard page
+    pub fn bottom(&self) -> usize {
+        stack_bottom(self.slot)
+    }
+
+    /// The address right above the stack
+    pub fn top(&self) -> usize {
+        self.bottom() + TASK_STACK_SIZE
+    }
+
+    pub fn size(&self) -> usize {
+        TASK_STACK_SIZE
+    }
+
+    /// The physical address right above the stack, for harts that start with paging disabled
+    pub fn physical_top(&self) -> usize {
+        self.frames + TASK_STACK_SIZE
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let mut slots = SLOTS.lock();
+        // A process's exit syscall runs on the process's interrupt stack, which is dropped with it
+        if (self.bottom()..self.top()).contains(&read_sp()) {
+            slots.deferred.push((load_hartid(), self.slot, self.frames));
+        } else {
+            free(&mut slots, self.slot, self.frames);
+        }
+        reap(&mut slots);
+    }
+}
@@ -1,8 +1,213 @@
 //! A logger for the kernel
+//!
+//! Besides printing them, it keeps the latest records in a fixed-size ring buffer
+//! (the logger is used before the heap is set up, so it can't allocate), which can be read
+//! from userspace with the kernel log handle backend.
 
-use log::{Level, Metadata, Record};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::{
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use log::{Level, LevelFilter, Metadata, Record};
+
+use crate::{
+    lock::shared::{Mutex, RwLock},
+    trap::in_interrupt_context,
+};
+
+const CRATE_NAME: &str = "rust_0bsd_riscv_kernel";
+const LOG_BUFFER_RECORDS: usize = 256;
+const LOG_RECORD_TEXT_SIZE: usize = 160;
+
+#[derive(Clone, Copy)]
+pub struct LogRecord {
+    pub sequence: usize,
+    pub time: u64,
+    pub hart_id: usize,
+    pub level: Level,
+    text: [u8; LOG_RECORD_TEXT_SIZE],
+    text_length: usize,
+}
+
+impl LogRecord {
+    const EMPTY: Self = Self {
+        sequence: 0,
+        time: 0,
+        hart_id: 0,
+        level: Level::Info,
+        text: [0; LOG_RECORD_TEXT_SIZE],
+        text_length: 0,
+    };
+
+    /// The module path and the message. Long messages are truncated
+    pub fn text(&self) -> &[u8] {
+        &self.text[..self.text_length]
+    }
+}
+
+impl Write for LogRecord {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let length = s.len().min(LOG_RECORD_TEXT_SIZE - self.text_length);
+        self.text[self.text_length..self.text_length + length]
+            .copy_from_slice(&s.as_bytes()[..length]);
+        self.text_length += length;
+        Ok(())
+    }
+}
+
+struct LogBuffer {
+    records: [LogRecord; LOG_BUFFER_RECORDS],
+    next_sequence: usize,
+    followers: Vec<Waker>,
+}
+
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    records: [LogRecord::EMPTY; LOG_BUFFER_RECORDS],
+    next_sequence: 0,
+    followers: Vec::new(),
+});
+
+/// Calls `f` with each record in the buffer starting at `sequence`, until it returns false.
+/// Records that have been overwritten are skipped. Returns the sequence number of the first record not passed to `f`.
+/// `f` runs on a copy of the records, so that the buffer isn't held if it faults on user memory
+pub fn read_records(sequence: usize, mut f: impl FnMut(&LogRecord) -> bool) -> usize {
+    let (first, records) = {
+        let buffer = LOG_BUFFER.lock();
+        let oldest = buffer.next_sequence.saturating_sub(LOG_BUFFER_RECORDS);
+        let first = sequence.max(oldest);
+        let records: Vec<LogRecord> = (first..buffer.next_sequence)
+            .map(|sequence| buffer.records[sequence % LOG_BUFFER_RECORDS])
+            .collect();
+        (first, records)
+    };
+    for (sequence, record) in (first..).zip(records.iter()) {
+        if !f(record) {
+            return sequence;
+        }
+    }
+    first + records.len()
+}
 
-use crate::{lock::shared::Mutex, trap::in_interrupt_context};
+/// Future that completes when there is a record with a sequence number of at least `sequence`
+pub struct NewRecordFuture {
+    sequence: usize,
+}
+
+impl Future for NewRecordFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut buffer = LOG_BUFFER.lock();
+        if buffer.next_sequence > self.sequence {
+            Poll::Ready(())
+        } else {
+            buffer.followers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+pub fn wait_for_record(sequence: usize) -> NewRecordFuture {
+    NewRecordFuture { sequence }
+}
+
+static DEFAULT_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+// Module path prefix (without the crate name) -> level
+static MODULE_LEVELS: RwLock<BTreeMap<String, LevelFilter>> = RwLock::new(BTreeMap::new());
+
+/// For levels that come from userspace
+pub fn level_filter_from_usize(level: usize) -> Option<LevelFilter> {
+    Some(match level {
+        0 => LevelFilter::Off,
+        1 => LevelFilter::Error,
+        2 => LevelFilter::Warn,
+        3 => LevelFilter::Info,
+        4 => LevelFilter::Debug,
+        5 => LevelFilter::Trace,
+        _ => return None,
+    })
+}
+
+/// Sets the log level for all modules whose path starts with `module` (for example, "drivers::virtio").
+/// An empty `module` sets the level used for modules that don't have one
+pub fn set_level(module: &str, level: LevelFilter) {
+    if module.is_empty() {
+        DEFAULT_LEVEL.store(level as usize, Ordering::Relaxed);
+    } else {
+        MODULE_LEVELS.write().insert(String::from(module), level);
+    }
+}
+
+fn module_path_in_crate(module_path: &str) -> &str {
+    module_path
+        .strip_prefix(CRATE_NAME)
+        .map(|path| path.strip_prefix("::").unwrap_or(path))
+        .unwrap_or(module_path)
+}
+
+fn level_for(module_path: &str) -> LevelFilter {
+    let module_path = module_path_in_crate(module_path);
+    MODULE_LEVELS
+        .read()
+        .iter()
+        .filter(|(prefix, _)| {
+            module_path == prefix.as_str()
+                || (module_path.starts_with(prefix.as_str())
+                    && module_path[prefix.len()..].starts_with("::"))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, level)| *level)
+        .unwrap_or_else(|| level_filter_from_usize(DEFAULT_LEVEL.load(Ordering::Relaxed)).unwrap())
+}
+
+fn this_hart_id() -> usize {
+    // sscratch isn't set up in early boot
+    if kernel_cpu::read_sscratch().is_null() {
+        0
+    } else {
+        kernel_cpu::load_hartid()
+    }
+}
+
+fn store_record(record: &Record) {
+    // The trap handler might have interrupted this hart while it held the buffer,
+    // and the record is still printed if it can't be stored
+    let mut buffer = if in_interrupt_context() {
+        match LOG_BUFFER.try_lock() {
+            Some(buffer) => buffer,
+            None => return,
+        }
+    } else {
+        LOG_BUFFER.lock()
+    };
+    let sequence = buffer.next_sequence;
+    let entry = &mut buffer.records[sequence % LOG_BUFFER_RECORDS];
+    *entry = LogRecord {
+        sequence,
+        time: kernel_cpu::read_time(),
+        hart_id: this_hart_id(),
+        level: record.level(),
+        ..LogRecord::EMPTY
+    };
+    let _ = write!(
+        entry,
+        "{}: {}",
+        module_path_in_crate(record.module_path().unwrap_or("")),
+        record.args()
+    );
+    buffer.next_sequence += 1;
+    let followers = core::mem::take(&mut buffer.followers);
+    // Followers lock the buffer when they're polled
+    drop(buffer);
+    for waker in followers {
+        waker.wake();
+    }
+}
 
 pub struct ColorfulLogger {
     lock: Mutex<()>,
@@ -10,11 +215,13 @@ pub struct ColorfulLogger {
 
 impl log::Log for ColorfulLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= Level::Info
+        metadata.level() <= level_for(metadata.target())
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
+        if record.level() <= level_for(record.module_path().unwrap_or("")) {
+            store_record(record);
+
             let prefix = match record.level() {
                 Level::Error => "\x1b[91;1mERROR\x1b[0m",
                 Level::Warn => "\x1b[93;1mWARN \x1b[0m",
@@ -30,7 +237,7 @@ impl log::Log for ColorfulLogger {
             };
             println!(
                 "{} [{}] {}",
-                &record.module_path().unwrap_or("")["rust_0bsd_riscv_kernel".len()..],
+                &record.module_path().unwrap_or("")[CRATE_NAME.len()..],
                 prefix,
                 record.args()
             );
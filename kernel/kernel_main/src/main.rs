@@ -111,6 +111,9 @@ pub fn main(hartid: usize, opaque: usize) -> ! {
 
     info!("Kernel reached, logging set up");
 
+    // Find out what the firmware can do
+    sbi::init();
+
     // SAFETY: identity_map is valid when the root page is valid, which in this case is true
     // and paging is disabled now
     #[cfg(target_arch = "riscv64")]
@@ -140,8 +143,8 @@ pub fn main(hartid: usize, opaque: usize) -> ! {
     
     
     cpu::fence_vma();
-    
-    get_time_setup();
+    #[cfg(target_arch = "riscv64")]
+    asid::init();
 
     //let virt_buffer = new_virtual_buffer(0x1000_0000, 0x4096);
 
@@ -151,6 +154,17 @@ pub fn main(hartid: usize, opaque: usize) -> ! {
     // Initialize the device tree assuming that opaque contains a pointer to the DT
     // (standard behaviour in QEMU)
     fdt::init(opaque as _);
+    time::init();
+    frame_allocator::init();
+    #[cfg(target_arch = "riscv64")]
+    {
+        kernel_stack::init();
+        // Move interrupts off the boot stack, which has no guard page
+        unsafe { BOOT_FRAME.interrupt_stack = hart::interrupt_stack_top(hartid) };
+    }
+
+    // This needs the frame allocator for page tables
+    get_time_setup();
 
     // Now that allocation and FDT is set up we can move the boot frame to a "proper" place
     let copied_frame = unsafe { BOOT_FRAME.clone() };
@@ -163,8 +177,6 @@ pub fn main(hartid: usize, opaque: usize) -> ! {
     // Set up the external interrupts
     let plic = Plic0::new_with_fdt();
     plic.set_threshold(0);
-    plic.set_enabled(10, false);
-    plic.set_priority(10, 6);
     plic.set_enabled(8, true);
     plic.set_priority(8, 3);
 
@@ -297,13 +309,13 @@ fn panic(info: &PanicInfo) -> ! {
     }
 
     // Shutdown immediately
-    sbi::shutdown(0);
+    sbi::shutdown(sbi::ResetReason::SystemFailure);
 
     loop {
         // Now (if we haven't shut down for some reason), poll the UART until we get a Ctrl+C
         // and then shutdown
         match unsafe { crate::drivers::uart::Uart::new(0x1000_0000).get() } {
-            Some(3) => crate::sbi::shutdown(0),
+            Some(3) => crate::sbi::shutdown(crate::sbi::ResetReason::NoReason),
             _ => {}
         }
     }
@@ -324,18 +336,23 @@ pub fn status_summary() {
             );
             println!("	{:?}", v.state);
         });
+    let (free, total) = frame_allocator::usage();
+    println!("Frames: {} KiB free of {} KiB", free / 1024, total / 1024);
 }
 
 pub mod allocator;
 pub mod as_register;
+pub mod asid;
 pub mod asm;
 pub mod context_switch;
+pub mod cpu_time;
 pub use kernel_cpu as cpu;
 pub mod device_setup;
 pub mod drivers;
 pub mod external_interrupt;
 pub mod fdt;
 pub mod filesystem;
+pub mod frame_allocator;
 pub mod future;
 pub mod handle;
 pub mod handle_backends;
@@ -343,6 +360,7 @@ pub mod hart;
 pub mod interrupt_context_waker;
 pub use kernel_io as io;
 pub mod kernel_debugging;
+pub mod kernel_stack;
 pub use kernel_lock as lock;
 pub mod benchmark;
 pub mod logger;
@@ -351,10 +369,13 @@ pub mod plic;
 pub mod process;
 pub mod sbi;
 pub mod scheduler;
+pub mod signal;
 pub mod syscall;
 pub mod test_task;
+pub mod time;
 pub mod timeout;
 pub mod timer_queue;
+pub mod tlb;
 pub mod trap;
 pub mod trap_frame;
 pub mod trap_future_executor;
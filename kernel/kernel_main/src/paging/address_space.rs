@@ -0,0 +1,88 @@
+//! The page tables of a user process, freed together with the process
+
+use kernel_cpu::csr::SATP_SV39;
+
+use super::{
+    new_table, satp_root_table, sv39::RootTable, EntryBits, Table, ENTRY_COUNT, GIGAPAGE_SIZE,
+    PAGE_SIZE, ROOT_PAGE,
+};
+use crate::{asid, cpu, frame_allocator, kernel_stack, tlb, virtual_buffers};
+
+/// Owns a root table, the tables below it and the user pages mapped in it.
+/// Kernel mappings (the ones without the USER bit) point to memory that isn't owned by the
+/// address space, so they're left alone when it's dropped. So are global tables, which are
+/// shared with the kernel's root table
+#[derive(Debug)]
+pub struct AddressSpace {
+    root: &'static mut Table,
+}
+
+impl AddressSpace {
+    /// Creates an address space with the kernel mappings that every process needs
+    pub fn new() -> Self {
+        let mut space = Self { root: new_table() };
+        virtual_buffers::initialize_root_table(&mut space.root_table());
+        kernel_stack::share_region(space.root);
+        space
+    }
+
+    pub fn root_table(&mut self) -> RootTable<'_> {
+        RootTable(&mut *self.root)
+    }
+
+    pub fn table(&self) -> &Table {
+        self.root
+    }
+
+    /// The satp value for this address space, without an ASID
+    pub fn satp(&self) -> usize {
+        (&*self.root as *const Table as usize) >> 12 | SATP_SV39
+    }
+}
+
+/// The satp value for the kernel's own page table
+pub fn kernel_satp() -> usize {
+    (unsafe { &ROOT_PAGE as *const Table as usize }) >> 12 | SATP_SV39
+}
+
+/// Releases the user frames and the tables below `table`.
+/// `page_size` is how much memory a leaf entry of this table maps
+unsafe fn free_entries(table: &mut Table, page_size: usize) {
+    for entry in table.entries.iter_mut() {
+        if entry.value & EntryBits::VALID == 0 {
+            continue;
+        }
+        if entry.value & EntryBits::GLOBAL != 0 {
+            entry.value = 0;
+            continue;
+        }
+        if let Some(child) = entry.try_as_table_mut() {
+            free_entries(child, page_size / ENTRY_COUNT);
+            frame_allocator::release(child as *mut Table as usize);
+        } else if entry.value & EntryBits::USER != 0 {
+            // Frames are reference counted one page at a time, like in AllocPages
+            for offset in (0..page_size).step_by(PAGE_SIZE) {
+                frame_allocator::release(entry.address() + offset);
+            }
+        }
+        entry.value = 0;
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let root = &*self.root as *const Table as usize;
+
+        // This hart is still using the table if the process exited through a syscall
+        if satp_root_table(cpu::read_satp()) == root {
+            unsafe { cpu::write_satp(kernel_satp()) };
+        }
+
+        // No hart can keep translations to frames that are about to be reused
+        tlb::shootdown(&*self.root, 0, usize::MAX);
+        asid::release(self.satp());
+
+        unsafe { free_entries(&mut *self.root, GIGAPAGE_SIZE) };
+        frame_allocator::release(root);
+    }
+}
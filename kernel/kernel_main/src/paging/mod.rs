@@ -5,6 +5,8 @@ use core::{
     ops::{Index, IndexMut},
 };
 
+#[cfg(target_arch = "riscv64")]
+pub mod address_space;
 pub mod sv32;
 #[cfg(target_arch = "riscv64")]
 pub mod sv39;
@@ -112,7 +114,7 @@ impl Entry {
     /// If this entry is a megapage, for example, the increment should be PAGE_SIZE
 
     pub unsafe fn split(&mut self, increment: usize) {
-        let mut table = Box::new(Table::zeroed());
+        let table = new_table();
         let mut current_address = self.value & EntryBits::ADDRESS_MASK;
 
         let flags = self.value & !(EntryBits::ADDRESS_MASK);
@@ -121,8 +123,7 @@ impl Entry {
             entry.value = flags | current_address;
             current_address += increment >> 2;
         }
-        self.value = 1 | ((&*table as *const Table as usize) >> 2);
-        Box::leak(table);
+        self.value = 1 | ((table as *const Table as usize) >> 2);
 
         debug_assert!(!self.is_leaf());
         debug_assert!(self.value & 1 != 0);
@@ -183,6 +184,14 @@ impl Table {
     }
 }
 
+/// Allocates a page table from the frame allocator
+pub fn new_table() -> &'static mut Table {
+    let address =
+        crate::frame_allocator::allocate_zeroed(0).expect("Out of memory for page tables");
+    // SAFETY: The frame is ours, identity mapped, and all zeroes is an empty table
+    unsafe { &mut *(address as *mut Table) }
+}
+
 impl Index<usize> for Table {
     type Output = Entry;
     fn index(&self, idx: usize) -> &Entry {
@@ -198,6 +207,8 @@ impl IndexMut<usize> for Table {
 
 pub trait Paging {
     fn map(&mut self, physical_addr: usize, virtual_addr: usize, length: usize, flags: usize);
+    /// Removes the mappings in the range, and flushes them from the TLBs of all harts using this table
+    fn unmap(&mut self, virtual_addr: usize, length: usize);
     unsafe fn query(&self, virtual_addr: usize) -> Result<(Entry, usize), PageLookupError>;
     unsafe fn query_physical_address(&self, virtual_addr: usize) -> Result<usize, PageLookupError> {
         self.query(virtual_addr).map(|(entry, offset)| entry.address() + offset)
@@ -237,10 +248,17 @@ use alloc::boxed::Box;
 
 use kernel_cpu::csr::{SATP_BARE, SATP_SV39};
 
+pub const SATP_PPN_MASK: usize = (1 << 44) - 1;
+
+/// The physical address of the root table that satp points to
+pub fn satp_root_table(satp: usize) -> usize {
+    (satp & SATP_PPN_MASK) << 12
+}
+
 pub trait PagingDebug: Paging + Debug {}
 pub fn get_active_root_table(satp: usize) -> Option<Box<dyn PagingDebug>> {
     let paging_type = (satp >> 60) << 60;
-    let table_addr = (satp & ((1 << 60) - 1)) << 12;
+    let table_addr = satp_root_table(satp);
     match paging_type {
         SATP_BARE => None,
         SATP_SV39 => unsafe {
@@ -1,4 +1,5 @@
-use core::arch::asm;
+use alloc::vec::Vec;
+use core::{arch::asm, ops::Range};
 
 use super::*;
 
@@ -40,6 +41,8 @@ impl<'a> Paging for RootTable<'a> {
         };
 
         let offset: usize = physical_addr.wrapping_sub(virtual_addr) >> 2;
+        // Whether a valid mapping got changed, in which case other harts might have it cached
+        let mut replaced = false;
 
         for vpn2 in vpn2_min..vpn2_max + 1 {
             let mut entry = &mut self.0.entries[vpn2];
@@ -68,37 +71,94 @@ impl<'a> Paging for RootTable<'a> {
                             let virt = vpn2 << 30 | vpn1 << 21 | vpn0 << 12;
                             // TODO: Write down somewhere that qemu doesn't care when using info mem
                             // if the high bits are set. Ask qemu to change this (later)
-                            entry.value = (virt >> 2 | flags).wrapping_add(offset) & (2usize.pow(54) - 1);
+                            let value =
+                                (virt >> 2 | flags).wrapping_add(offset) & (2usize.pow(54) - 1);
+                            replaced |= replaces_mapping(entry, value);
+                            entry.value = value;
                             //println!("  vp0 {} {:x} {:?}", vpn0, virt, entry);
                             //println!("newval {:x}", entry.value);
                         }
                     } else {
                         //println!("oldval {:?}", entry);
                         //println!("virt {:x}", (vpn2 << 30 | vpn1 << 21));
-                        entry.value = (vpn2 << 28 | vpn1 << 19 | flags).wrapping_add(offset) & (2usize.pow(54) - 1);
+                        let value = (vpn2 << 28 | vpn1 << 19 | flags).wrapping_add(offset)
+                            & (2usize.pow(54) - 1);
+                        replaced |= replaces_mapping(entry, value);
+                        entry.value = value;
                         //println!("newval {:?}", entry);
                     }
                 }
             } else {
                 //println!("oldval {:x}", entry.value);
                 //println!("virt {:x}", (vpn2 << 30));
-                entry.value = (vpn2 << 28 | flags).wrapping_add(offset) & (2usize.pow(54) - 1);
+                let value = (vpn2 << 28 | flags).wrapping_add(offset) & (2usize.pow(54) - 1);
+                replaced |= replaces_mapping(entry, value);
+                entry.value = value;
                 //println!("newval {:x}", entry.value);
             }
         }
 
         //println!("{:?}", "finish");
 
-        unsafe { asm!("sfence.vma") };
-        unsafe { asm!("fence rw, rw") };
+        if replaced {
+            crate::tlb::shootdown(&*self.0, virtual_addr, length);
+        } else {
+            unsafe { asm!("sfence.vma") };
+            unsafe { asm!("fence rw, rw") };
+        }
 
         //info!("entry");
     }
+    fn unmap(&mut self, virtual_addr: usize, length: usize) {
+        let end = virtual_addr + length;
+        let mut address = virtual_addr - virtual_addr % PAGE_SIZE;
+        while address < end {
+            let vpn2 = (address >> 30) & (ENTRY_COUNT - 1);
+            let vpn1 = (address >> 21) & (ENTRY_COUNT - 1);
+            let vpn0 = (address >> 12) & (ENTRY_COUNT - 1);
+
+            let entry = &mut self.0.entries[vpn2];
+            if entry.value & EntryBits::VALID == 0 {
+                address = (address / GIGAPAGE_SIZE + 1) * GIGAPAGE_SIZE;
+                continue;
+            }
+            if entry.is_leaf() {
+                if address % GIGAPAGE_SIZE == 0 && address + GIGAPAGE_SIZE <= end {
+                    entry.value = 0;
+                    address += GIGAPAGE_SIZE;
+                    continue;
+                }
+                // Only part of the gigapage is unmapped
+                unsafe { entry.split(MEGAPAGE_SIZE) };
+            }
+
+            let table = unsafe { entry.as_table_mut() };
+            let entry = &mut table[vpn1];
+            if entry.value & EntryBits::VALID == 0 {
+                address = (address / MEGAPAGE_SIZE + 1) * MEGAPAGE_SIZE;
+                continue;
+            }
+            if entry.is_leaf() {
+                if address % MEGAPAGE_SIZE == 0 && address + MEGAPAGE_SIZE <= end {
+                    entry.value = 0;
+                    address += MEGAPAGE_SIZE;
+                    continue;
+                }
+                unsafe { entry.split(PAGE_SIZE) };
+            }
+
+            let table = unsafe { entry.as_table_mut() };
+            table[vpn0].value = 0;
+            address += PAGE_SIZE;
+        }
+
+        crate::tlb::shootdown(&*self.0, virtual_addr, length);
+    }
     unsafe fn query(&self, virtual_addr: usize) -> Result<(Entry, usize), PageLookupError> {
         let vpn2 = (virtual_addr >> 30) & (ENTRY_COUNT - 1);
         let vpn1 = (virtual_addr >> 21) & (ENTRY_COUNT - 1);
         let vpn0 = (virtual_addr >> 12) & (ENTRY_COUNT - 1);
-        let offset = virtual_addr & (ENTRY_COUNT - 1);
+        let offset = virtual_addr & (PAGE_SIZE - 1);
         let table = &self.0;
         if let Some(table) = table[vpn2].try_as_table() {
             if let Some(table) = table[vpn1].try_as_table() {
@@ -111,12 +171,15 @@ impl<'a> Paging for RootTable<'a> {
             } else if table[vpn1].value & EntryBits::VALID == 0 {
                 Err(PageLookupError::Invalid)
             } else {
-                Ok((table[vpn1], offset + vpn0 * ENTRY_COUNT))
+                Ok((table[vpn1], offset + vpn0 * PAGE_SIZE))
             }
         } else if table[vpn2].value & EntryBits::VALID == 0 {
             Err(PageLookupError::Invalid)
         } else {
-            Ok((table[vpn2], offset + vpn0 * ENTRY_COUNT + vpn1 * ENTRY_COUNT * ENTRY_COUNT))
+            Ok((
+                table[vpn2],
+                offset + vpn0 * PAGE_SIZE + vpn1 * MEGAPAGE_SIZE,
+            ))
         }
     }
     fn identity_map(&mut self) {
@@ -127,3 +190,83 @@ impl<'a> Paging for RootTable<'a> {
 }
 
 impl<'a> PagingDebug for RootTable<'a> {}
+
+/// Whether writing `new_value` to the entry removes or changes a mapping that might be cached,
+/// like remapping to another address or downgrading permissions
+fn replaces_mapping(entry: &Entry, new_value: usize) -> bool {
+    let ignored = EntryBits::ACCESSED | EntryBits::DIRTY;
+    entry.value & EntryBits::VALID != 0 && (entry.value & !ignored) != (new_value & !ignored)
+}
+
+/// Sv39 addresses at or above this belong to the upper half, which is never mapped for user mode
+pub const USER_HALF_END: usize = 1 << 38;
+
+/// Whether user processes can map pages from `start` to `start + size`. The range has to be in the
+/// user half and outside of the kernel stacks, which every address space shares
+pub fn is_user_range(start: usize, size: usize) -> bool {
+    match start.checked_add(size) {
+        Some(end) => end <= USER_HALF_END && !crate::kernel_stack::overlaps_region(start..end),
+        None => false,
+    }
+}
+
+/// The user pages mapped in a page-aligned range, as (virtual address, physical address) pairs.
+/// Invalid entries are skipped whole, so sparse ranges don't have to be walked page by page
+pub fn user_pages_in(root: &Table, range: Range<usize>) -> Vec<(usize, usize)> {
+    fn walk(
+        table: &Table,
+        base: usize,
+        page_size: usize,
+        range: &Range<usize>,
+        pages: &mut Vec<(usize, usize)>,
+    ) {
+        for (index, entry) in table.entries.iter().enumerate() {
+            let entry_start = base + index * page_size;
+            let entry_end = entry_start + page_size;
+            // Global entries belong to the kernel, even when the tables under them are shared here
+            if entry_end <= range.start
+                || entry_start >= range.end
+                || entry.value & EntryBits::VALID == 0
+                || entry.value & EntryBits::GLOBAL != 0
+            {
+                continue;
+            }
+            if let Some(table) = unsafe { entry.try_as_table() } {
+                walk(table, entry_start, page_size / ENTRY_COUNT, range, pages);
+            } else if entry.value & EntryBits::USER != 0 {
+                let first = entry_start.max(range.start);
+                let last = entry_end.min(range.end);
+                pages.extend(
+                    (first..last)
+                        .step_by(PAGE_SIZE)
+                        .map(|page| (page, entry.address() + page - entry_start)),
+                );
+            }
+        }
+    }
+    let mut pages = Vec::new();
+    walk(root, 0, GIGAPAGE_SIZE, &range, &mut pages);
+    pages
+}
+
+/// How many bytes of memory the table maps as accessible to user mode
+pub fn user_mapped_size(root: &Table) -> usize {
+    fn table_size(table: &Table, page_size: usize) -> usize {
+        table
+            .entries
+            .iter()
+            .map(|entry| {
+                if entry.value & EntryBits::VALID == 0 {
+                    0
+                } else if let Some(table) = unsafe { entry.try_as_table() } {
+                    table_size(table, page_size / ENTRY_COUNT)
+                } else if entry.value & EntryBits::USER != 0 {
+                    page_size
+                } else {
+                    0
+                }
+            })
+            .sum()
+    }
+    table_size(root, GIGAPAGE_SIZE)
+}
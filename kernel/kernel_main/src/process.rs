@@ -10,20 +10,27 @@ use core::{
     arch::asm,
     future::Future,
     pin::Pin,
-    sync::atomic::AtomicUsize,
-    task::{RawWaker, RawWakerVTable, Waker},
+    sync::atomic::{AtomicU64, AtomicUsize},
+    task::{Context, Poll, RawWaker, RawWakerVTable, Waker},
 };
 
+use kernel_syscall_abi::ABNORMAL_TERMINATION;
+
 use crate::{
     asm::do_supervisor_syscall_0,
     context_switch,
-    cpu::{self, load_hartid, read_sscratch, Registers},
+    cpu::{self, load_hartid, Registers},
     handle::Handle,
     hart::get_this_hart_meta,
-    lock::shared::RwLock,
-    scheduler::schedule_next_slice,
+    kernel_stack::{KernelStack, StackOwner},
+    lock::shared::{Mutex, RwLock},
+    paging::address_space::AddressSpace,
+    scheduler::{self, schedule_next_slice},
+    signal::SignalContext,
+    timer_queue::{self, TimerEventCause},
     trap::{in_interrupt_context, use_boot_frame_if_necessary},
     trap_frame::{TrapFrame, TrapFrameExt},
+    trap_future_executor::BlockedSyscall,
 };
 
 pub const TASK_STACK_SIZE: usize = 4096 * 8;
@@ -34,7 +41,6 @@ pub const PROCESS_WAKER_VTABLE: RawWakerVTable = RawWakerVTable::new(
     /* drop */ Process::waker_drop,
 );
 pub static PROCESSES: RwLock<BTreeMap<usize, PidSlot>> = RwLock::new(BTreeMap::new());
-pub static PROCESS_SCHED_QUEUE: RwLock<Vec<Weak<RwLock<Process>>>> = RwLock::new(Vec::new());
 
 pub enum PidSlot {
     Allocated,
@@ -74,6 +80,57 @@ pub enum ProcessState {
     Scheduled,
 }
 
+/// Holds the exit code of a process once it dies, and lets other processes wait for it.
+/// It's shared with whoever wants to know about the process, so it outlives it
+#[derive(Debug, Default)]
+pub struct ExitStatus {
+    code: Mutex<Option<usize>>,
+    waiters: Mutex<Vec<Waker>>,
+}
+
+impl ExitStatus {
+    /// Only the first call has any effect
+    pub fn set(&self, code: usize) {
+        {
+            let mut current = self.code.lock();
+            if current.is_some() {
+                return;
+            }
+            *current = Some(code);
+        }
+        for waker in core::mem::take(&mut *self.waiters.lock()) {
+            waker.wake();
+        }
+    }
+
+    pub fn code(&self) -> Option<usize> {
+        *self.code.lock()
+    }
+
+    pub fn wait(self: &Arc<Self>) -> ExitFuture {
+        ExitFuture(self.clone())
+    }
+}
+
+/// Completes with the exit code when the process dies
+pub struct ExitFuture(Arc<ExitStatus>);
+
+impl Future for ExitFuture {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Hold the waiter lock, so that the code can't be set between checking it and registering the waker
+        let mut waiters = self.0.waiters.lock();
+        match self.0.code() {
+            Some(code) => Poll::Ready(code),
+            None => {
+                waiters.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Process {
     /// The process ID of the process can be fetched by getting trap_frame.pid
@@ -86,9 +143,37 @@ pub struct Process {
 
     /// For supervisor mode the kernel initially creates a small stack page for this process
     /// This is where it's stored
-    pub kernel_allocated_stack: Option<Box<[u8; TASK_STACK_SIZE]>>,
+    pub kernel_allocated_stack: Option<KernelStack>,
+    /// The stack that supervisor processes handle traps on
+    pub kernel_interrupt_stack: Option<KernelStack>,
+    /// The page tables of user processes. Dropping it frees the process's memory
+    pub address_space: Option<AddressSpace>,
 
     pub user_id: u64,
+
+    pub exit_status: Arc<ExitStatus>,
+
+    /// Bitmask of signals that have been sent to this process but not delivered yet
+    pub pending_signals: usize,
+    pub signal_handler: Option<usize>,
+    /// Set while the signal handler is running
+    pub signal_context: Option<SignalContext>,
+    /// Set while the process waits for a syscall to complete, so that it can be killed
+    pub blocked_syscall: Option<BlockedSyscall>,
+
+    /// Time spent running user code and in the kernel on behalf of this process, in timer ticks
+    pub user_time: AtomicU64,
+    pub kernel_time: AtomicU64,
+
+    /// From MIN_NICE (highest priority) to MAX_NICE
+    pub nice: isize,
+    /// Time spent running, scaled down by the weight of the nice level. See `scheduler`
+    pub virtual_runtime: AtomicU64,
+
+    /// The process itself, to put it in run queues
+    pub this: Weak<RwLock<Process>>,
+    /// Bitmask of the harts that this process can run on (bit N = hart ID N)
+    pub allowed_harts: usize,
 }
 
 extern "C" {
@@ -110,6 +195,21 @@ impl Process {
         }
         false
     }
+    /// User memory for user processes, and the stacks the kernel allocated for supervisor processes
+    pub fn memory_usage(&self) -> usize {
+        if self.is_supervisor {
+            self.kernel_allocated_stack
+                .iter()
+                .chain(self.kernel_interrupt_stack.iter())
+                .map(|stack| stack.size())
+                .sum()
+        } else {
+            self.address_space
+                .as_ref()
+                .map(|space| crate::paging::sv39::user_mapped_size(space.table()))
+                .unwrap_or(0)
+        }
+    }
     pub fn can_be_scheduled(&self) -> bool {
         match self.state {
             ProcessState::Pending => true,
@@ -125,22 +225,14 @@ impl Process {
         // The hart ID that we will be executing in is the same one as the current one.
         self.trap_frame.hartid = load_hartid();
 
-        // Use the same stack for interrupts
-        self.trap_frame.interrupt_stack = unsafe { (*read_sscratch()).interrupt_stack };
+        // Processes without an interrupt stack of their own use the hart's
+        if self.kernel_interrupt_stack.is_none() {
+            self.trap_frame.interrupt_stack = crate::hart::interrupt_stack_top(load_hartid());
+        }
 
         // Get a raw pointer to the Box's data (which is the trap frame)
         let frame_pointer =
             Pin::as_ref(&self.trap_frame).get_ref() as *const TrapFrame as *mut TrapFrame;
-        /*
-            let (time, perf, cycle): (usize, usize, usize);
-
-            unsafe { asm!("csrr {0}, time", out(reg)(time),) };
-            unsafe { asm!("csrr {0}, instret", out(reg)(perf),) };
-            unsafe { asm!("csrr {0}, cycle", out(reg)(cycle),) };
-
-            //debug!("{:?} {:?} {:?}", perf, cycle, time);
-        */
-
         debug!(
             "Switch to frame at \x1b[32m{:?}\x1b[0m (PC {:x} NAME {:?} HART {})",
             frame_pointer,
@@ -195,8 +287,10 @@ impl Process {
     unsafe fn waker_wake_by_ref(data: *const ()) {
         // The box re-acquires ownership of the RwLock<Self>
         let process: Box<Weak<RwLock<Self>>> = Box::from_raw(data as _);
-        let process_internal = process.upgrade().expect("Waited process is gone!");
-        process_internal.write().make_pending_when_possible();
+        // The process might have been killed while it was waiting
+        if let Some(process_internal) = process.upgrade() {
+            process_internal.write().make_pending_when_possible();
+        }
         // Make the box lose ownership of the RwLock<Self>
         Box::leak(process);
     }
@@ -211,7 +305,7 @@ impl Process {
     pub fn make_pending_when_possible(&mut self) {
         match self.state {
             ProcessState::Yielded => {
-                self.state = ProcessState::Pending;
+                scheduler::make_pending(self);
             }
             _ => {
                 self.no_op_yield_count
@@ -296,7 +390,7 @@ pub fn finish_executing_process(pid: usize) {
         // Boot process can't be stopped
         return;
     }
-    try_get_process(&pid).write().state = ProcessState::Pending;
+    scheduler::make_pending(&mut try_get_process(&pid).write());
     debug!("Made process pending");
 }
 
@@ -334,27 +428,49 @@ pub fn new_process(constructor: impl FnOnce(&mut Process)) -> usize {
         trap_frame: trapframe_box,
         state: ProcessState::Pending,
         kernel_allocated_stack: None,
+        kernel_interrupt_stack: None,
+        address_space: None,
         name: None,
         no_op_yield_count: AtomicUsize::new(0),
         user_id: 0,
+        exit_status: Arc::new(ExitStatus::default()),
+        pending_signals: 0,
+        signal_handler: None,
+        signal_context: None,
+        blocked_syscall: None,
+        user_time: AtomicU64::new(0),
+        kernel_time: AtomicU64::new(0),
+        nice: 0,
+        virtual_runtime: AtomicU64::new(
+            scheduler::MIN_VIRTUAL_RUNTIME.load(core::sync::atomic::Ordering::Acquire),
+        ),
+        this: Weak::new(),
+        allowed_harts: usize::MAX,
     };
 
+    process.trap_frame.pid = pid;
     constructor(&mut process);
 
-    process.trap_frame.pid = pid;
     process.trap_frame.use_current_satp_as_kernel_satp();
     if process.is_supervisor {
         process.trap_frame.satp = process.trap_frame.kernel_satp;
+    } else {
+        let space = process
+            .address_space
+            .as_ref()
+            .expect("User processes need an address space");
+        process.trap_frame.satp = crate::asid::register(space.satp());
     }
     process.trap_frame.hartid = 0xBADC0DE;
 
-    // Wrap the process in a lock
-    let process = RwLock::new(process);
-    // Move the process into an Arc
-    let process = Arc::new(process);
+    // Wrap the process in a lock and move it into an Arc
+    let process = Arc::new_cyclic(|this| {
+        process.this = this.clone();
+        RwLock::new(process)
+    });
 
     // Schedule the process
-    PROCESS_SCHED_QUEUE.write().push(Arc::downgrade(&process));
+    scheduler::enqueue(&process.read());
 
     guard.insert(pid, PidSlot::Used(process));
 
@@ -367,25 +483,17 @@ pub fn new_supervisor_process_int(function: usize, a0: usize) -> usize {
         process.trap_frame.general_registers[Registers::Ra.idx()] =
             process_return_address_supervisor as usize;
 
-        let process_stack = kernel_util::boxed_slice_with_alignment(4096, 4096, &0u8);
-        process.trap_frame.interrupt_stack =
-            process_stack.as_ptr() as usize + TASK_STACK_SIZE - 0x10;
+        let pid = process.trap_frame.pid;
+        let interrupt_stack = KernelStack::new(StackOwner::Process(pid));
+        process.trap_frame.interrupt_stack = interrupt_stack.top() - 0x10;
+        process.kernel_interrupt_stack = Some(interrupt_stack);
 
-        Box::leak(process_stack);
-
-        let process_stack = kernel_util::boxed_slice_with_alignment(TASK_STACK_SIZE, 4096, &0u8);
-        process.trap_frame.general_registers[Registers::Sp.idx()] =
-            process_stack.as_ptr() as usize + TASK_STACK_SIZE - 0x10;
+        let process_stack = KernelStack::new(StackOwner::Process(pid));
+        process.trap_frame.general_registers[Registers::Sp.idx()] = process_stack.top() - 0x10;
+        process.kernel_allocated_stack = Some(process_stack);
 
         process.trap_frame.general_registers[Registers::A0.idx()] = a0;
         process.trap_frame.pc = function;
-
-        use core::convert::TryInto;
-        process.kernel_allocated_stack = Some(
-            process_stack
-                .try_into()
-                .expect("Process stack has incorrect length!"),
-        );
     })
 }
 
@@ -420,11 +528,27 @@ pub fn new_supervisor_process_with_name(function: fn(), name: String) -> usize {
     pid
 }
 
+/// Records the exit code of the process and deletes it
+pub fn exit_process(pid: usize, code: usize) {
+    try_get_process(&pid).read().exit_status.set(code);
+    delete_process(pid);
+}
+
+/// Deletes the process. If no exit code was recorded, it is recorded as an abnormal termination
 pub fn delete_process(pid: usize) {
     // If our trap frame is the same one as the process's trap frame,
     // change sscratch to use the boot trap frame
     // (since the current sscratch is held by the Process struct and will deallocated soon)
     use_boot_frame_if_necessary(&*try_get_process(&pid).read().trap_frame as _);
+    // Close the process's handles, since backends may share them with other processes
+    let handles = core::mem::take(&mut try_get_process(&pid).write().handles);
+    for (_, handle) in handles {
+        let _ = handle.close(&[]);
+    }
+    try_get_process(&pid)
+        .read()
+        .exit_status
+        .set(ABNORMAL_TERMINATION);
     // We don't need to remove from the sched queue here.
     // That gets done on context switching
     PROCESSES.write().remove(&pid);
@@ -479,7 +603,15 @@ pub fn useful_process_count() -> usize {
 }
 
 pub fn idle_entry_point() {
-    cpu::wfi();
+    // Interrupts are disabled (sstatus.SIE) between checking for work and the wfi, so that an IPI can't be handled
+    // in between. wfi still returns when an interrupt is pending, and it's handled once they're enabled again
+    unsafe { cpu::write_sstatus(cpu::read_sstatus() & !(1 << 1)) };
+    let meta = get_this_hart_meta().unwrap();
+    if meta.is_idle.load(core::sync::atomic::Ordering::SeqCst) {
+        crate::hart::idle_wait(&meta);
+    }
+    drop(meta);
+    unsafe { cpu::write_sstatus(cpu::read_sstatus() | 1 << 1) };
     get_this_hart_meta().unwrap().set_idle_process(None);
     unsafe { do_supervisor_syscall_0(1) };
 }
@@ -491,6 +623,7 @@ pub fn idle_forever_entry_point() {
 }
 
 /// Starts a process that wfi()s once, immediately switches to the process, then exits.
+/// The hart doesn't get timer ticks meanwhile.
 /// Must be called from an interrupt context.
 pub fn idle() -> ! {
     let pid = {
@@ -502,7 +635,7 @@ pub fn idle() -> ! {
             let mut process = process.write();
             crate::trap::use_boot_frame_if_necessary(&*process.trap_frame as _);
             if process.state == ProcessState::Running {
-                process.state = ProcessState::Pending;
+                scheduler::make_pending(&mut process);
             }
         }
         if let Some(pid) = get_this_hart_meta().unwrap().get_idle_process() {
@@ -512,11 +645,15 @@ pub fn idle() -> ! {
                 idle_entry_point,
                 format!("Idle process for hart {}", load_hartid()),
             );
+            // Other harts have their own idle process
+            try_get_process(&pid).write().allowed_harts = 1 << load_hartid();
             get_this_hart_meta().unwrap().set_idle_process(Some(pid));
             pid
         }
     };
     assert!(try_get_process(&pid).read().is_supervisor == true);
-    schedule_next_slice(1);
+    // There's nothing to preempt, so don't tick. The hart wakes up on IPIs and its other timer events
+    timer_queue::cancel(TimerEventCause::ContextSwitch);
+    timer_queue::schedule_next();
     context_switch::context_switch(&pid)
 }
@@ -1,6 +1,12 @@
 //! Abstractions over the RISC-V Supervisor Binary Interface to communicate with M-mode code
 // See https://github.com/riscv/riscv-sbi-doc/blob/master/riscv-sbi.adoc
-use core::arch::asm;
+//
+// `init` uses the Base extension to find out which extensions the firmware implements.
+// When an extension is missing, the functions here fall back to the legacy (v0.1) calls if there's one.
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
 #[repr(isize)]
 #[derive(Debug)]
@@ -36,70 +42,85 @@ impl SBIError {
     }
 }
 
-pub unsafe fn call_sbi_0(extension_id: usize, function_id: usize) -> Result<usize, SBIError> {
-    let error_code: usize;
-    let return_value: usize;
-    asm!(r"
-		mv a7, {2}
-		mv a6, {3}
-		ecall
-		mv {0}, a0
-		mv {1}, a1
-	", out(reg) error_code, out(reg) return_value, in(reg) extension_id, in(reg) function_id);
+/// Extension IDs
+pub mod extension {
+    pub const BASE: usize = 0x10;
+    pub const TIME: usize = 0x54494D45;
+    pub const IPI: usize = 0x735049;
+    pub const RFENCE: usize = 0x52464E43;
+    pub const HSM: usize = 0x48534D;
+    pub const SRST: usize = 0x53525354;
+    pub const DBCN: usize = 0x4442434E;
 
-    if error_code == 0 {
-        Ok(return_value)
-    } else {
-        Err(SBIError::from_isize(core::mem::transmute(return_value)))
-    }
+    // Legacy extensions. Each one is a single function
+    pub const LEGACY_SET_TIMER: usize = 0x00;
+    pub const LEGACY_CONSOLE_PUTCHAR: usize = 0x01;
+    pub const LEGACY_CONSOLE_GETCHAR: usize = 0x02;
+    pub const LEGACY_SEND_IPI: usize = 0x04;
+    pub const LEGACY_REMOTE_FENCE_I: usize = 0x05;
+    pub const LEGACY_REMOTE_SFENCE_VMA: usize = 0x06;
+    pub const LEGACY_REMOTE_SFENCE_VMA_ASID: usize = 0x07;
+    pub const LEGACY_SHUTDOWN: usize = 0x08;
+
+    /// Extensions that `init` probes for, with their names
+    pub const PROBED: [(usize, &str); 6] = [
+        (TIME, "TIME"),
+        (IPI, "IPI"),
+        (RFENCE, "RFENCE"),
+        (HSM, "HSM"),
+        (SRST, "SRST"),
+        (DBCN, "DBCN"),
+    ];
 }
 
-pub unsafe fn call_sbi_1(
+pub unsafe fn call_sbi_5(
     extension_id: usize,
     function_id: usize,
     a0: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    a4: usize,
 ) -> Result<usize, SBIError> {
     let error_code: usize;
     let return_value: usize;
-    asm!(r"
-		mv a7, {2}
-		mv a6, {3}
-		mv a0, {4}
-		ecall
-		mv {0}, a0
-		mv {1}, a1
-	", out(reg) error_code, out(reg) return_value, in(reg) extension_id, in(reg) function_id, in(reg) a0);
+    asm!(
+        "ecall",
+        inlateout("a0") a0 => error_code,
+        inlateout("a1") a1 => return_value,
+        in("a2") a2,
+        in("a3") a3,
+        in("a4") a4,
+        in("a6") function_id,
+        in("a7") extension_id,
+    );
 
     if error_code == 0 {
         Ok(return_value)
     } else {
-        Err(SBIError::from_isize(core::mem::transmute(return_value)))
+        Err(SBIError::from_isize(error_code as isize))
     }
 }
 
+pub unsafe fn call_sbi_0(extension_id: usize, function_id: usize) -> Result<usize, SBIError> {
+    call_sbi_5(extension_id, function_id, 0, 0, 0, 0, 0)
+}
+
+pub unsafe fn call_sbi_1(
+    extension_id: usize,
+    function_id: usize,
+    a0: usize,
+) -> Result<usize, SBIError> {
+    call_sbi_5(extension_id, function_id, a0, 0, 0, 0, 0)
+}
+
 pub unsafe fn call_sbi_2(
     extension_id: usize,
     function_id: usize,
     a0: usize,
     a1: usize,
 ) -> Result<usize, SBIError> {
-    let error_code: usize;
-    let return_value: usize;
-    asm!(r"
-		mv a7, {2}
-		mv a6, {3}
-		mv a0, {4}
-		mv a1, {5}
-		ecall
-		mv {0}, a0
-		mv {1}, a1
-	", out(reg) error_code, out(reg) return_value, in(reg) extension_id, in(reg) function_id, in(reg) a0, in(reg) a1);
-
-    if error_code == 0 {
-        Ok(return_value)
-    } else {
-        Err(SBIError::from_isize(core::mem::transmute(return_value)))
-    }
+    call_sbi_5(extension_id, function_id, a0, a1, 0, 0, 0)
 }
 
 pub unsafe fn call_sbi_3(
@@ -109,50 +130,398 @@ pub unsafe fn call_sbi_3(
     a1: usize,
     a2: usize,
 ) -> Result<usize, SBIError> {
-    let error_code: usize;
+    call_sbi_5(extension_id, function_id, a0, a1, a2, 0, 0)
+}
+
+/// Legacy calls only return a value in a0, which is negative on errors for most of them
+pub unsafe fn call_legacy_sbi(
+    extension_id: usize,
+    a0: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+) -> isize {
     let return_value: usize;
-    asm!(r"
-		mv a7, {2}
-		mv a6, {3}
-		mv a0, {4}
-		mv a1, {5}
-		mv a2, {6}
-		ecall
-		mv {0}, a0
-		mv {1}, a1
-	", out(reg) error_code, out(reg) return_value, in(reg) extension_id, in(reg) function_id, in(reg) a0, in(reg) a1, in(reg) a2);
+    asm!(
+        "ecall",
+        inlateout("a0") a0 => return_value,
+        inlateout("a1") a1 => _,
+        in("a2") a2,
+        in("a3") a3,
+        in("a7") extension_id,
+    );
+    return_value as isize
+}
 
-    if error_code == 0 {
-        Ok(return_value)
+fn legacy_result(return_value: isize) -> Result<(), SBIError> {
+    if return_value == 0 {
+        Ok(())
     } else {
-        Err(SBIError::from_isize(core::mem::transmute(return_value)))
+        Err(SBIError::from_isize(return_value))
+    }
+}
+
+static PROBED: AtomicBool = AtomicBool::new(false);
+/// Bit N is set if extension::PROBED[N] is available
+static AVAILABLE_EXTENSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the firmware implements an extension. Before `init`, all extensions are assumed to be available
+pub fn has_extension(extension_id: usize) -> bool {
+    if !PROBED.load(Ordering::Acquire) {
+        return true;
+    }
+    extension::PROBED
+        .iter()
+        .position(|(id, _)| *id == extension_id)
+        .map(|index| AVAILABLE_EXTENSIONS.load(Ordering::Relaxed) & 1 << index != 0)
+        .unwrap_or(false)
+}
+
+pub fn spec_version() -> Result<(usize, usize), SBIError> {
+    let version = unsafe { call_sbi_0(extension::BASE, 0) }?;
+    Ok(((version >> 24) & 0x7F, version & 0xFF_FFFF))
+}
+
+pub fn implementation_id() -> Result<usize, SBIError> {
+    unsafe { call_sbi_0(extension::BASE, 1) }
+}
+
+pub fn implementation_version() -> Result<usize, SBIError> {
+    unsafe { call_sbi_0(extension::BASE, 2) }
+}
+
+/// Returns 0 if the extension isn't available, or an extension-specific nonzero value if it is
+pub fn probe_extension(extension_id: usize) -> Result<usize, SBIError> {
+    unsafe { call_sbi_1(extension::BASE, 3, extension_id) }
+}
+
+fn implementation_name(id: usize) -> &'static str {
+    match id {
+        0 => "BBL",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        7 => "Xen",
+        8 => "PolarFire HSS",
+        _ => "unknown",
+    }
+}
+
+/// Finds out which extensions the firmware implements. Firmware older than v0.2 doesn't have
+/// the Base extension, so only legacy calls are used in that case
+pub fn init() {
+    let version = match spec_version() {
+        Ok(version) => version,
+        Err(_) => {
+            warn!("SBI v0.1 firmware, only legacy calls are available");
+            PROBED.store(true, Ordering::Release);
+            return;
+        }
+    };
+    let implementation = implementation_id().unwrap_or(usize::MAX);
+    info!(
+        "SBI v{}.{}, implementation: {} ({}) version {:x}",
+        version.0,
+        version.1,
+        implementation_name(implementation),
+        implementation,
+        implementation_version().unwrap_or(0)
+    );
+
+    let mut available = 0;
+    for (index, (id, name)) in extension::PROBED.iter().enumerate() {
+        if probe_extension(*id).map(|v| v != 0).unwrap_or(false) {
+            available |= 1 << index;
+            info!("SBI extension {} is available", name);
+        } else {
+            info!("SBI extension {} is missing", name);
+        }
     }
+    AVAILABLE_EXTENSIONS.store(available, Ordering::Relaxed);
+    PROBED.store(true, Ordering::Release);
 }
 
 pub fn set_absolute_timer(time: u64) -> Result<(), SBIError> {
     // SAFETY: Assuming the SBI implementation is correct, setting a timer shouldn't cause anything bad in memory
     // Note that this SBI call's return value is meaningless, so we erase it
     // TODO: Use RV32 ABI for u64's here
-    unsafe { call_sbi_1(0x54494D45, 0, time as usize).map(|_| {}) }
+    if has_extension(extension::TIME) {
+        unsafe { call_sbi_1(extension::TIME, 0, time as usize).map(|_| {}) }
+    } else {
+        unsafe { call_legacy_sbi(extension::LEGACY_SET_TIMER, time as usize, 0, 0, 0) };
+        Ok(())
+    }
 }
 
 pub fn set_relative_timer(time: u64) -> Result<(), SBIError> {
     set_absolute_timer(crate::timeout::get_time() + time)
 }
 
-pub fn shutdown(reason: usize) {
-    // SAFETY: Shutting down is safe, because the whole machine state gets erased. But destructors don't get called
+/// A set of harts: bit N of `mask` is hart ID base + N
+#[derive(Debug, Clone, Copy)]
+pub struct HartMask {
+    pub mask: usize,
+    pub base: usize,
+}
+
+impl HartMask {
+    pub fn all() -> Self {
+        // A base of -1 means all harts
+        Self {
+            mask: 0,
+            base: usize::MAX,
+        }
+    }
+
+    pub fn single(hart_id: usize) -> Self {
+        Self {
+            mask: 1,
+            base: hart_id,
+        }
+    }
+
+    /// Bit N = hart ID N
+    pub fn from_mask(mask: usize) -> Self {
+        Self { mask, base: 0 }
+    }
+
+    /// The mask that legacy calls take (relative to hart 0), if the harts can be represented that way
+    fn legacy_mask(&self) -> Option<usize> {
+        if self.base == usize::MAX {
+            Some(usize::MAX)
+        } else if self.mask == 0 {
+            Some(0)
+        } else if self.base < usize::BITS as usize
+            && self.mask.leading_zeros() as usize >= self.base
+        {
+            Some(self.mask << self.base)
+        } else {
+            None
+        }
+    }
+
+    /// Calls a legacy function that takes a pointer to the mask in a0
+    fn call_legacy(
+        &self,
+        extension_id: usize,
+        a1: usize,
+        a2: usize,
+        a3: usize,
+    ) -> Result<(), SBIError> {
+        let mask = self.legacy_mask().ok_or(SBIError::InvalidParam)?;
+        // SAFETY: The firmware only reads the mask
+        legacy_result(unsafe {
+            call_legacy_sbi(extension_id, &mask as *const usize as usize, a1, a2, a3)
+        })
+    }
+}
+
+/// Sends a supervisor software interrupt to the harts in `harts`
+pub fn send_ipi(harts: HartMask) -> Result<(), SBIError> {
+    if has_extension(extension::IPI) {
+        // SAFETY: An IPI only sets the SSIP bit on the other harts
+        unsafe { call_sbi_2(extension::IPI, 0, harts.mask, harts.base).map(|_| {}) }
+    } else {
+        harts.call_legacy(extension::LEGACY_SEND_IPI, 0, 0, 0)
+    }
+}
+
+/// Makes the harts execute fence.i
+pub fn remote_fence_i(harts: HartMask) -> Result<(), SBIError> {
+    if has_extension(extension::RFENCE) {
+        unsafe { call_sbi_2(extension::RFENCE, 0, harts.mask, harts.base).map(|_| {}) }
+    } else {
+        harts.call_legacy(extension::LEGACY_REMOTE_FENCE_I, 0, 0, 0)
+    }
+}
+
+/// Makes the harts execute sfence.vma for the addresses from `start` to `start + size`.
+/// A size of usize::MAX flushes everything
+pub fn remote_sfence_vma(harts: HartMask, start: usize, size: usize) -> Result<(), SBIError> {
+    if has_extension(extension::RFENCE) {
+        unsafe {
+            call_sbi_5(extension::RFENCE, 1, harts.mask, harts.base, start, size, 0).map(|_| {})
+        }
+    } else {
+        harts.call_legacy(extension::LEGACY_REMOTE_SFENCE_VMA, start, size, 0)
+    }
+}
+
+/// Like remote_sfence_vma, but only for the address space `asid`
+pub fn remote_sfence_vma_asid(
+    harts: HartMask,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> Result<(), SBIError> {
+    if has_extension(extension::RFENCE) {
+        unsafe {
+            call_sbi_5(
+                extension::RFENCE,
+                2,
+                harts.mask,
+                harts.base,
+                start,
+                size,
+                asid,
+            )
+            .map(|_| {})
+        }
+    } else {
+        harts.call_legacy(extension::LEGACY_REMOTE_SFENCE_VMA_ASID, start, size, asid)
+    }
+}
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy)]
+pub enum ResetType {
+    Shutdown = 0,
+    ColdReboot = 1,
+    WarmReboot = 2,
+}
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy)]
+pub enum ResetReason {
+    NoReason = 0,
+    SystemFailure = 1,
+}
+
+/// Shuts down or reboots the machine. Only returns if it failed.
+/// Without the SRST extension, only shutting down is possible
+pub fn system_reset(reset_type: ResetType, reason: ResetReason) -> SBIError {
+    // SAFETY: Resetting is safe, because the whole machine state gets erased. But destructors don't get called
     unsafe {
-        // See https://github.com/riscv/riscv-sbi-doc/blob/master/riscv-sbi.adoc#system-reset-extension-eid-0x53525354-srst
-        print!("{:?}", call_sbi_2(0x53525354, 0, 0, reason));
+        if has_extension(extension::SRST) {
+            match call_sbi_2(extension::SRST, 0, reset_type as usize, reason as usize) {
+                Ok(_) => SBIError::Success,
+                Err(e) => e,
+            }
+        } else if let ResetType::Shutdown = reset_type {
+            call_legacy_sbi(extension::LEGACY_SHUTDOWN, 0, 0, 0, 0);
+            SBIError::Failed
+        } else {
+            SBIError::NotSupported
+        }
+    }
+}
+
+/// Only returns if shutting down failed
+pub fn shutdown(reason: ResetReason) {
+    let error = system_reset(ResetType::Shutdown, reason);
+    error!("Couldn't shut down: {:?}", error);
+}
+
+/// Only returns if rebooting failed
+pub fn reboot(reason: ResetReason) {
+    let error = system_reset(ResetType::ColdReboot, reason);
+    error!("Couldn't reboot: {:?}", error);
+}
+
+/// Writes bytes to the firmware's debug console and returns how many were written.
+/// `bytes` has to be in identity mapped memory, since the firmware takes its physical address
+pub fn debug_console_write(bytes: &[u8]) -> Result<usize, SBIError> {
+    if has_extension(extension::DBCN) {
+        let address = bytes.as_ptr() as usize;
+        unsafe { call_sbi_3(extension::DBCN, 0, bytes.len(), address, 0) }
+    } else {
+        for byte in bytes {
+            unsafe { call_legacy_sbi(extension::LEGACY_CONSOLE_PUTCHAR, *byte as usize, 0, 0, 0) };
+        }
+        Ok(bytes.len())
+    }
+}
+
+/// Reads the bytes that are available from the firmware's debug console, and returns how many were read.
+/// `buffer` has to be in identity mapped memory, since the firmware takes its physical address
+pub fn debug_console_read(buffer: &mut [u8]) -> Result<usize, SBIError> {
+    if has_extension(extension::DBCN) {
+        let address = buffer.as_mut_ptr() as usize;
+        unsafe { call_sbi_3(extension::DBCN, 1, buffer.len(), address, 0) }
+    } else {
+        let mut read = 0;
+        for byte in buffer.iter_mut() {
+            // Returns -1 when there's nothing to read
+            match unsafe { call_legacy_sbi(extension::LEGACY_CONSOLE_GETCHAR, 0, 0, 0, 0) } {
+                value if value < 0 => break,
+                value => *byte = value as u8,
+            }
+            read += 1;
+        }
+        Ok(read)
+    }
+}
+
+pub fn debug_console_write_byte(byte: u8) -> Result<(), SBIError> {
+    if has_extension(extension::DBCN) {
+        unsafe { call_sbi_1(extension::DBCN, 2, byte as usize).map(|_| {}) }
+    } else {
+        unsafe { call_legacy_sbi(extension::LEGACY_CONSOLE_PUTCHAR, byte as usize, 0, 0, 0) };
+        Ok(())
+    }
+}
+
+// Hart states returned by hart_get_status
+pub const HART_STARTED: usize = 0;
+pub const HART_STOPPED: usize = 1;
+pub const HART_SUSPENDED: usize = 4;
+
+// Suspend types for hart_suspend
+/// The hart keeps its registers, and hart_suspend returns when an interrupt arrives (like wfi)
+pub const RETENTIVE_SUSPEND: u32 = 0x0000_0000;
+/// The hart loses its registers, and resumes at the address passed to hart_suspend
+pub const NON_RETENTIVE_SUSPEND: u32 = 0x8000_0000;
+
+// The HSM extension has no legacy equivalent
+fn require_hsm() -> Result<(), SBIError> {
+    if has_extension(extension::HSM) {
+        Ok(())
+    } else {
+        Err(SBIError::NotSupported)
     }
 }
 
 /// Safety: Only if start_addr is an address capable of bootstrapping himself
 pub unsafe fn start_hart(hartid: usize, start_addr: usize, opaque: usize) -> Result<(), SBIError> {
-    call_sbi_3(0x48534D, 0, hartid, start_addr, opaque).map(|_| {})
+    require_hsm()?;
+    call_sbi_3(extension::HSM, 0, hartid, start_addr, opaque).map(|_| {})
+}
+
+/// Stops this hart. Only returns if it failed
+/// Safety: Nothing else can be running on this hart, and it can't be holding locks
+pub unsafe fn stop_hart() -> SBIError {
+    if let Err(e) = require_hsm() {
+        return e;
+    }
+    match call_sbi_0(extension::HSM, 1) {
+        Ok(_) => SBIError::Success,
+        Err(e) => e,
+    }
 }
 
 pub fn hart_get_status(hartid: usize) -> Result<usize, SBIError> {
-    unsafe { call_sbi_1(0x48534D, 2, hartid) }
+    require_hsm()?;
+    unsafe { call_sbi_1(extension::HSM, 2, hartid) }
+}
+
+/// Puts this hart in a low power state until an interrupt arrives.
+/// For non-retentive suspends, the hart resumes at `resume_addr` with `opaque` in a1 instead of returning
+/// Safety: For non-retentive suspends, resume_addr has to be able to bootstrap the hart
+pub unsafe fn hart_suspend(
+    suspend_type: u32,
+    resume_addr: usize,
+    opaque: usize,
+) -> Result<(), SBIError> {
+    require_hsm()?;
+    call_sbi_3(
+        extension::HSM,
+        3,
+        suspend_type as usize,
+        resume_addr,
+        opaque,
+    )
+    .map(|_| {})
 }
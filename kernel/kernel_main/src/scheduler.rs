@@ -1,70 +1,308 @@
 // 0BSD
 
+//! Chooses which process runs next
+//!
+//! Every process has a nice level, which determines its weight. The time a process runs is charged to
+//! its virtual runtime, scaled down by its weight, and the process with the lowest virtual runtime runs next.
+//! Processes that spend most of their time waiting (like the shell) have a low virtual runtime,
+//! so they run as soon as they are woken up even if other processes are busy.
+//!
+//! Each hart has its own run queue with the processes that are ready to run, so harts don't contend
+//! on a global lock. Harts whose queue is empty steal processes from the busiest hart.
+//! Processes only run on the harts in their allowed harts mask (bit N = hart ID N).
+//!
+//! Harts with nothing to run don't get timer ticks. Instead, harts that queue a process send an IPI
+//! to an idle hart that can run it.
+
+use alloc::{
+    collections::BTreeMap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+pub use kernel_syscall_abi::{MAX_NICE, MIN_NICE};
+
 use crate::{
-    process::{ProcessState, PROCESS_SCHED_QUEUE},
-    timeout,
+    cpu::load_hartid,
+    hart::{self, get_hart_meta, get_this_hart_meta, HartMeta, HART_META},
+    lock::shared::RwLock,
+    process::{try_get_process, Process, ProcessState},
+    time::{ticks_from_duration, Instant},
     timer_queue::{self, schedule_at_or_earlier},
 };
 
-// Return the next PID to be run
-pub fn schedule() -> usize {
-    let mut process_sched_queue = PROCESS_SCHED_QUEUE.write();
-    let mut pid = 0;
-
-    // Using a vector would be too expensive to store
-    // removed indices, so we'll just store a range between min idx and max idx
-
-    // usize::MAX means no value
-    let mut removed_index_min = usize::MAX;
-    let mut removed_index_max = usize::MAX;
-
-    for (idx, this_process) in process_sched_queue.iter().enumerate() {
-        match this_process.upgrade() {
-            // The process still exists
-            Some(strong) => {
-                let mut lock = strong.write();
-                if lock.can_be_scheduled() {
-                    pid = lock.trap_frame.pid;
-                    lock.state = ProcessState::Scheduled;
-                    break;
-                }
-            }
-            // The process doesn't exist anymore. Remove it from the sched queue
-            None => {
-                if removed_index_min == usize::MAX {
-                    removed_index_max = idx;
-                    removed_index_min = idx;
-                } else if removed_index_max == idx - 1 {
-                    removed_index_max = idx;
-                }
-                // Else, it means that the processes that we want to remove are non-contiguous
-                // It's probably more worth it to just wait until the next iteration
-            }
+/// Length of the time slice of a process with nice level 0
+const BASE_SLICE: Duration = Duration::from_millis(100);
+const NICE_0_WEIGHT: u64 = 1024;
+
+// Each nice level is about 1.25 times the weight of the next one (same values as Linux)
+const NICE_WEIGHTS: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+/// The lowest virtual runtime of the processes that have been scheduled. Processes that were waiting
+/// for a long time start from slightly below this, so that they don't monopolize the harts after waking up
+pub static MIN_VIRTUAL_RUNTIME: AtomicU64 = AtomicU64::new(0);
+
+pub fn nice_weight(nice: isize) -> u64 {
+    NICE_WEIGHTS[(nice.clamp(MIN_NICE, MAX_NICE) - MIN_NICE) as usize]
+}
+
+/// How much running for `elapsed` ticks adds to the virtual runtime of a process with this nice level
+pub fn virtual_runtime_for(elapsed: u64, nice: isize) -> u64 {
+    elapsed * NICE_0_WEIGHT / nice_weight(nice)
+}
+
+/// In timer ticks
+fn base_slice() -> u64 {
+    ticks_from_duration(BASE_SLICE)
+}
+
+/// Length of the time slice of a process with this nice level, in timer ticks
+pub fn time_slice(nice: isize) -> u64 {
+    let base_slice = base_slice();
+    (base_slice * nice_weight(nice) / NICE_0_WEIGHT).clamp(base_slice / 8, base_slice * 4)
+}
+
+/// Processes that are ready to run on a hart, ordered by virtual runtime.
+/// Each hart has one in its HartMeta
+pub struct RunQueue {
+    // (virtual runtime, PID) -> process
+    processes: BTreeMap<(u64, usize), QueuedProcess>,
+}
+
+struct QueuedProcess {
+    process: Weak<RwLock<Process>>,
+    // The allowed harts of the process when it was queued, so that other harts know if they can steal it
+    allowed_harts: usize,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        Self {
+            processes: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.processes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.processes.is_empty()
+    }
+
+    fn pop(&mut self) -> Option<Weak<RwLock<Process>>> {
+        self.processes.pop_first().map(|(_, queued)| queued.process)
+    }
+
+    /// Like pop, but only takes processes that are allowed to run on `hart_id`
+    fn pop_for_hart(&mut self, hart_id: usize) -> Option<Weak<RwLock<Process>>> {
+        let key = *self
+            .processes
+            .iter()
+            .find(|(_, queued)| is_allowed(queued.allowed_harts, hart_id))?
+            .0;
+        self.processes.remove(&key).map(|queued| queued.process)
+    }
+}
+
+pub fn is_allowed(allowed_harts: usize, hart_id: usize) -> bool {
+    hart_id < usize::BITS as usize && allowed_harts & (1 << hart_id) != 0
+}
+
+/// Whether a mask of allowed harts contains at least one hart that exists
+pub fn allows_any_hart(allowed_harts: usize) -> bool {
+    HART_META
+        .read()
+        .keys()
+        .any(|hart_id| is_allowed(allowed_harts, *hart_id))
+}
+
+/// The hart whose run queue a process that became ready goes into, and its hart ID.
+/// Processes go back to the hart they last ran on, and new processes go to the least busy hart.
+/// Only harts in the process's allowed harts are considered
+fn choose_hart(process: &Process) -> Option<(usize, Arc<HartMeta>)> {
+    if is_allowed(process.allowed_harts, process.trap_frame.hartid) {
+        if let Some(meta) = get_hart_meta(process.trap_frame.hartid) {
+            return Some((process.trap_frame.hartid, meta));
         }
     }
+    HART_META
+        .read()
+        .iter()
+        .filter(|(hart_id, _)| is_allowed(process.allowed_harts, **hart_id))
+        .min_by_key(|(_, meta)| meta.run_queue.lock().len())
+        .map(|(hart_id, meta)| (*hart_id, meta.clone()))
+}
 
-    if removed_index_min != usize::MAX {
-        // Each iteration, the index of the removed process will shift 1 lower
-        // so we don't need to value of the iteration (the one that's replaced by an underscore here)
-        for _ in removed_index_min..removed_index_max + 1 {
-            process_sched_queue.remove(removed_index_min);
+/// Wakes up a hart to run a process that was just queued on `queued_on`:
+/// that hart if it's idle, or else an idle hart that can steal it
+fn kick_idle_hart(queued_on: usize, allowed_harts: usize) {
+    let this_hart = load_hartid();
+    let idle_hart = {
+        let harts = HART_META.read();
+        let is_idle = |meta: &HartMeta| meta.is_idle.load(Ordering::SeqCst);
+        if harts
+            .get(&queued_on)
+            .map(|meta| is_idle(meta))
+            .unwrap_or(false)
+        {
+            Some(queued_on)
+        } else {
+            harts
+                .iter()
+                .find(|(hart_id, meta)| is_allowed(allowed_harts, **hart_id) && is_idle(meta))
+                .map(|(hart_id, _)| *hart_id)
+        }
+    };
+    match idle_hart {
+        // This hart looks for something to run when it leaves the trap handler
+        Some(hart_id) if hart_id == this_hart => {
+            get_this_hart_meta()
+                .unwrap()
+                .is_idle
+                .store(false, Ordering::SeqCst);
         }
+        Some(hart_id) => hart::wake_hart(hart_id),
+        None => {}
+    }
+}
+
+/// Puts a Pending process in the run queue of a hart
+pub fn enqueue(process: &Process) {
+    let (hart_id, meta) = match choose_hart(process) {
+        Some(hart) => hart,
+        // All its allowed harts are offline, so it runs anywhere
+        None => HART_META
+            .read()
+            .iter()
+            .next()
+            .map(|(hart_id, meta)| (*hart_id, meta.clone()))
+            .expect("No harts to run the process on"),
+    };
+    // Processes that were waiting for a long time start from slightly below everyone else
+    let floor = MIN_VIRTUAL_RUNTIME
+        .load(Ordering::Acquire)
+        .saturating_sub(base_slice());
+    let virtual_runtime = process
+        .virtual_runtime
+        .fetch_max(floor, Ordering::Relaxed)
+        .max(floor);
+    meta.run_queue.lock().processes.insert(
+        (virtual_runtime, process.trap_frame.pid),
+        QueuedProcess {
+            process: process.this.clone(),
+            allowed_harts: process.allowed_harts,
+        },
+    );
+    kick_idle_hart(hart_id, process.allowed_harts);
+}
+
+/// Makes the process Pending so that it gets scheduled eventually
+pub fn make_pending(process: &mut Process) {
+    if process.state == ProcessState::Pending {
+        // Already in a run queue
+        return;
     }
+    process.state = ProcessState::Pending;
+    enqueue(process);
+}
 
-    if pid == 0 {
-        // Don't schedule anything
-        return 0;
+/// Moves the processes queued on a hart that is going offline to other harts.
+/// The hart has to be removed from HART_META first
+pub fn migrate_run_queue(meta: &HartMeta) {
+    loop {
+        let process = match meta.run_queue.lock().pop() {
+            Some(process) => process,
+            None => break,
+        };
+        if let Some(process) = process.upgrade() {
+            let process = process.read();
+            if process.state == ProcessState::Pending {
+                enqueue(&process);
+            }
+        }
     }
+}
 
-    process_sched_queue.rotate_left(1);
+/// Takes a process that can run on this hart from the run queue of the busiest other hart
+fn steal(this_hart: usize) -> Option<Weak<RwLock<Process>>> {
+    let mut victims: Vec<_> = HART_META
+        .read()
+        .iter()
+        .filter(|(hart_id, _)| **hart_id != this_hart)
+        .map(|(_, meta)| (meta.run_queue.lock().len(), meta.clone()))
+        .filter(|(len, _)| *len != 0)
+        .collect();
+    victims.sort_by_key(|(len, _)| core::cmp::Reverse(*len));
+    victims
+        .into_iter()
+        .find_map(|(_, meta)| meta.run_queue.lock().pop_for_hart(this_hart))
+}
 
-    pid
+// Return the next PID to be run
+pub fn schedule() -> usize {
+    let this_hart = load_hartid();
+    let meta = get_this_hart_meta().unwrap();
+    loop {
+        let process = {
+            let next = meta.run_queue.lock().pop();
+            match next.or_else(|| steal(this_hart)) {
+                Some(process) => process,
+                // Don't schedule anything
+                None => return 0,
+            }
+        };
+        // Processes that don't exist anymore are just dropped from the queue
+        let process = match process.upgrade() {
+            Some(process) => process,
+            None => continue,
+        };
+        let mut lock = process.write();
+        if !lock.can_be_scheduled() {
+            continue;
+        }
+        if meta.get_idle_process() == Some(lock.trap_frame.pid) {
+            // Idle processes only run when there's nothing else to run, see process::idle
+            continue;
+        }
+        if !is_allowed(lock.allowed_harts, this_hart) && allows_any_hart(lock.allowed_harts) {
+            // Its allowed harts changed while it was queued.
+            // If they're all offline, it runs here anyway
+            enqueue(&lock);
+            continue;
+        }
+        lock.state = ProcessState::Scheduled;
+        MIN_VIRTUAL_RUNTIME.fetch_max(
+            lock.virtual_runtime.load(Ordering::Relaxed),
+            Ordering::AcqRel,
+        );
+        return lock.trap_frame.pid;
+    }
 }
 
 pub fn schedule_next_slice(slices: u64) {
     use timer_queue::{TimerEvent, TimerEventCause::*};
     schedule_at_or_earlier(TimerEvent {
-        instant: timeout::get_time() + slices * 1_000_000,
+        instant: (Instant::now() + BASE_SLICE * slices as u32).ticks(),
+        cause: ContextSwitch,
+    });
+}
+
+/// Replaces the current time slice with a new one for `pid`, whose length depends on its nice level
+pub fn start_slice_for(pid: &usize) {
+    use timer_queue::{TimerEvent, TimerEventCause::*};
+    let slice = time_slice(try_get_process(pid).read().nice);
+    timer_queue::replace(TimerEvent {
+        instant: Instant::now().ticks() + slice,
         cause: ContextSwitch,
     });
+    timer_queue::schedule_next();
 }
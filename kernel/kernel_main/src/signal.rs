@@ -0,0 +1,115 @@
+//! Asynchronous notifications between processes
+//!
+//! Sending a signal only marks it as pending in the target process.
+//! Signals are delivered by `context_switch` right before the process runs again:
+//! either the process is terminated, or its registers are saved and it jumps to its signal handler.
+//! The handler ends with the SignalReturn syscall, which restores the saved registers.
+
+use kernel_syscall_abi::signal::{Signal, SignalError};
+
+use crate::{
+    cpu::Registers,
+    process::{try_get_process, weak_get_process, Process, ProcessState},
+    scheduler,
+    trap_frame::TrapFrame,
+};
+
+/// What the process was doing before it started running its signal handler
+#[derive(Debug)]
+pub struct SignalContext {
+    general_registers: [usize; 32],
+    pc: usize,
+}
+
+pub fn send_signal(sender: usize, target: usize, signal: Signal) -> Result<(), SignalError> {
+    let sender_user_id = try_get_process(&sender).read().user_id;
+    let target = weak_get_process(&target)
+        .upgrade()
+        .ok_or(SignalError::NoSuchProcess)?;
+    let mut target = target.write();
+
+    if target.is_supervisor || (sender_user_id != 0 && sender_user_id != target.user_id) {
+        return Err(SignalError::NoPermission);
+    }
+
+    target.pending_signals |= 1 << signal as usize;
+
+    if target.state == ProcessState::Yielded {
+        let terminates = signal == Signal::Kill
+            || (target.signal_handler.is_none() && signal.terminates_by_default());
+        match target.blocked_syscall.take() {
+            // Blocked syscalls are only interrupted to terminate the process.
+            // Otherwise, the handler runs once the syscall returns
+            Some(syscall) if terminates => {
+                if syscall.cancel() {
+                    scheduler::make_pending(&mut target);
+                } else {
+                    target.blocked_syscall = Some(syscall);
+                }
+            }
+            Some(syscall) => target.blocked_syscall = Some(syscall),
+            // The process yielded by itself, so it can be woken up
+            None => scheduler::make_pending(&mut target),
+        }
+    }
+    Ok(())
+}
+
+/// Called right before switching to a process. If the process has pending signals and a signal handler,
+/// this makes it run the handler. Returns true if the process has to be terminated instead
+pub fn deliver_pending_signals(process: &mut Process) -> bool {
+    if process.pending_signals == 0 {
+        return false;
+    }
+    if process.pending_signals & (1 << Signal::Kill as usize) != 0 {
+        return true;
+    }
+
+    for bit in 0..usize::BITS as usize {
+        if process.pending_signals & (1 << bit) == 0 {
+            continue;
+        }
+        let signal = match Signal::try_from(bit) {
+            Ok(signal) => signal,
+            Err(_) => {
+                process.pending_signals &= !(1 << bit);
+                continue;
+            }
+        };
+        match process.signal_handler {
+            None if signal.terminates_by_default() => return true,
+            None => {
+                // Ignore it
+                process.pending_signals &= !(1 << bit);
+            }
+            Some(handler) => {
+                if process.signal_context.is_some() {
+                    // Wait until the current handler returns
+                    return false;
+                }
+                process.pending_signals &= !(1 << bit);
+                let frame = &mut process.trap_frame;
+                process.signal_context = Some(SignalContext {
+                    general_registers: frame.general_registers,
+                    pc: frame.pc,
+                });
+                frame.pc = handler;
+                frame.general_registers[Registers::A0.idx()] = signal as usize;
+                return false;
+            }
+        }
+    }
+    false
+}
+
+/// Restores the registers that the process had before its signal handler was called
+pub fn signal_return(frame: &mut TrapFrame) -> Result<(), SignalError> {
+    let context = try_get_process(&frame.pid)
+        .write()
+        .signal_context
+        .take()
+        .ok_or(SignalError::NotInHandler)?;
+    frame.general_registers = context.general_registers;
+    frame.pc = context.pc;
+    Ok(())
+}
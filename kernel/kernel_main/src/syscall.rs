@@ -1,10 +1,17 @@
+use core::time::Duration;
+
+use kernel_as_register::{AsRegister, EncodedError};
 use kernel_syscall_abi::*;
 
 use crate::{
     context_switch,
     cpu::{write_satp, Registers},
-    paging::{EntryBits, Paging},
+    paging::{
+        sv39::{is_user_range, user_pages_in, USER_HALF_END},
+        EntryBits, Paging,
+    },
     process::{self, try_get_process},
+    timeout,
     trap_frame::{TrapFrame, TrapFrameExt},
     trap_future_executor::block_and_return_to_userspace,
 };
@@ -20,108 +27,66 @@ pub fn do_syscall(frame: *mut TrapFrame) {
     use SyscallNumbers::*;
     match number {
         Exit => {
-            syscall_exit(frame, 0);
+            let code = frame.general_registers[Registers::A0.idx()];
+            syscall_exit(frame, code);
         }
         Yield => {
             syscall_yield(frame);
         }
         AllocPages => {
+            let result = alloc_pages(frame);
+            set_return_value(frame, result.map_err(|e| e.as_register()));
+        }
+        FreePages => {
             let virtual_address = frame.general_registers[Registers::A0.idx()];
-            let physical_addr = frame.general_registers[Registers::A1.idx()];
-            let size = frame.general_registers[Registers::A2.idx()];
-            let mut flags = frame.general_registers[Registers::A3.idx()];
-            flags = (flags & !EntryBits::ADDRESS_MASK) | EntryBits::USER;
-
-            // TODO fix aliasing issues!
-            let mut root_table = unsafe { frame.satp_as_sv39_root_table() };
-
-            let physical_addr = if physical_addr == usize::MAX {
-                let new_pages = kernel_util::boxed_slice_with_alignment(size, 4096, &0u8);
-                let physical_addr = &new_pages[0] as *const u8 as usize;
-                core::mem::forget(new_pages);
-                physical_addr
-            } else {
-                if try_get_process(&mut frame.pid).read().user_id == 0 {
-                    physical_addr
-                } else {
-                    unimplemented!(
-                        "Mapping virtual address space to physical address space provided by user"
-                    )
-                }
-            };
+            let size = frame.general_registers[Registers::A1.idx()];
+
+            match virtual_address.checked_add(size) {
+                None => set_return_value(frame, Err(AllocPagesError::InvalidRange.as_register())),
+                Some(end) => {
+                    // Only user pages can be freed, the rest of the table belongs to the kernel
+                    let start = virtual_address.min(USER_HALF_END).div_floor(4096) * 4096;
+                    let end = end.min(USER_HALF_END).div_ceil(4096) * 4096;
 
-            let virtual_address = if virtual_address == usize::MAX {
-                // Find a free set of contiguous pages
-                let mut run_length = 0;
-                let mut first_page_in_set = None;
-                for i in (0x1000..0x80000000).step_by(4096) {
-                    if let Ok(page) = unsafe { root_table.query(i) } {
-                        // This page is used
-                        if page.0.value & EntryBits::USER != 0 {
-                            run_length = 0;
-                            continue;
+                    let mut root_table = unsafe { frame.satp_as_sv39_root_table() };
+                    let user_pages = user_pages_in(root_table.0, start..end);
+
+                    // Unmap runs of consecutive pages together, for one TLB shootdown per run
+                    let mut index = 0;
+                    while index < user_pages.len() {
+                        let run_start = user_pages[index].0;
+                        let mut run_end = run_start + 4096;
+                        index += 1;
+                        while index < user_pages.len() && user_pages[index].0 == run_end {
+                            run_end += 4096;
+                            index += 1;
                         }
+                        root_table.unmap(run_start, run_end - run_start);
                     }
+                    core::mem::forget(root_table);
 
-                    // This page is free and unmapped
-                    if run_length >= size {
-                        first_page_in_set = Some(i - run_length);
-                        break;
+                    // The translations are gone everywhere now, so the frames can be reused
+                    for (_, physical_addr) in user_pages {
+                        crate::frame_allocator::release(physical_addr);
                     }
-                    run_length += 4096;
+                    set_return_value(frame, Ok(0));
                 }
-                first_page_in_set.expect("No free page found!")
-            } else {
-                virtual_address
-            };
-            
-            let size = size.div_ceil(4096) * 4096;
-            let paging_flags = flags & EntryBits::RWX;
-
-            root_table.map(
-                physical_addr,
-                virtual_address,
-                size,
-                paging_flags | EntryBits::VALID | EntryBits::USER,
-            );
-            core::mem::forget(root_table);
-            frame.general_registers[Registers::A0.idx()] = virtual_address;
+            }
         }
-        FreePages => {}
 
         Open => {
             let current_pid = frame.pid;
             let fut = async move {
-                use alloc::sync::Arc;
-
-                use crate::handle::Handle;
-
                 let id = frame.general_registers[Registers::A0.idx()];
                 let options =
                     &frame.general_registers[Registers::A1.idx()..Registers::A7.idx() + 1];
 
                 let process = crate::process::try_get_process(&frame.pid);
-                let new_fd_number = process
-                    .write()
-                    .handles
-                    .last_key_value()
-                    .map(|s| s.0 + 1)
-                    .unwrap_or(1);
-
-                let backend_instance =
-                    crate::handle_backends::open(&id, &new_fd_number, options).await;
-
-                match backend_instance {
-                    Ok(backend_instance) => {
-                        process.write().handles.insert(
-                            new_fd_number,
-                            Handle {
-                                fd_id: new_fd_number,
-                                backend: Arc::downgrade(&backend_instance),
-                                backend_meta: 0,
-                            },
-                        );
-                        core::mem::forget(backend_instance.clone());
+                let new_fd_number = crate::handle::next_fd_number(&process.read().handles);
+
+                match crate::handle_backends::open_handle(&id, new_fd_number, options).await {
+                    Ok(handle) => {
+                        process.write().handles.insert(new_fd_number, handle);
                         frame.general_registers[Registers::A0.idx()] = new_fd_number;
                         frame.general_registers[Registers::A1.idx()] = 0;
                     }
@@ -150,17 +115,18 @@ pub fn do_syscall(frame: *mut TrapFrame) {
                 let options =
                     &frame.general_registers[Registers::A3.idx()..Registers::A7.idx() + 1];
 
-                let backend = {
+                let (backend, backend_id) = {
                     let process = crate::process::try_get_process(&frame.pid);
                     let process = process.write();
-                    process.handles[&id].backend.upgrade()
+                    let handle = &process.handles[&id];
+                    (handle.backend.upgrade(), handle.backend_meta)
                 };
-                backend
+                let result = backend
                     .as_ref()
                     .unwrap()
-                    .write(&id, buf, options)
-                    .await
-                    .unwrap();
+                    .write(&backend_id, buf, options)
+                    .await;
+                set_return_value(frame, result);
             };
             block_and_return_to_userspace(current_pid, alloc::boxed::Box::pin(fut));
         }
@@ -178,26 +144,18 @@ pub fn do_syscall(frame: *mut TrapFrame) {
                 };
                 let options =
                     &frame.general_registers[Registers::A3.idx()..Registers::A7.idx() + 1];
-                let backend = {
+                let (backend, backend_id) = {
                     let process = crate::process::try_get_process(&frame.pid);
                     let process = process.write();
-                    process.handles[&id].backend.upgrade()
+                    let handle = &process.handles[&id];
+                    (handle.backend.upgrade(), handle.backend_meta)
                 };
-                let result = backend.as_ref().unwrap().read(&id, buf, options).await;
-                match result {
-                    Ok(o) => {
-                        frame.general_registers[Registers::A0.idx()] = o;
-                        frame.general_registers[Registers::A1.idx()] = 0;
-                    }
-                    Err(e) => {
-                        frame.general_registers[Registers::A0.idx()] = usize::MAX;
-                        frame.general_registers[Registers::A1.idx()] = e.0;
-                        // Copy the other parameters
-                        frame.general_registers[Registers::A2.idx()
-                            ..(Registers::A2.idx() + e.1.len()).min(Registers::A7.idx())]
-                            .copy_from_slice(&e.1);
-                    }
-                }
+                let result = backend
+                    .as_ref()
+                    .unwrap()
+                    .read(&backend_id, buf, options)
+                    .await;
+                set_return_value(frame, result);
             };
             block_and_return_to_userspace(current_pid, alloc::boxed::Box::pin(fut));
         }
@@ -206,13 +164,89 @@ pub fn do_syscall(frame: *mut TrapFrame) {
 
             let options = &frame.general_registers[Registers::A1.idx()..Registers::A7.idx() + 1];
 
-            let backend = {
-                let process = crate::process::try_get_process(&frame.pid);
-                let process = process.write();
-                process.handles[&id].backend.upgrade()
-            };
+            let handle = crate::process::try_get_process(&frame.pid)
+                .write()
+                .handles
+                .remove(&id);
 
-            backend.unwrap().close(&id, &options);
+            if let Some(handle) = handle {
+                let _ = handle.close(options);
+            }
+        }
+        Duplicate => {
+            let current_pid = frame.pid;
+            let fut = async move {
+                // a0 = fd to duplicate
+                // a1 = fd of a process egg to give the new fd to, or usize::MAX to keep it in this process
+                // a2 = fd number for the new fd, or usize::MAX for any free one
+                let fd = frame.general_registers[Registers::A0.idx()];
+                let destination = frame.general_registers[Registers::A1.idx()];
+                let slot = frame.general_registers[Registers::A2.idx()];
+                let result = duplicate_handle(frame.pid, fd, destination, slot).await;
+                set_return_value(frame, result);
+            };
+            block_and_return_to_userspace(current_pid, alloc::boxed::Box::pin(fut));
+        }
+        Signal => {
+            // a0 = fd of the process to send the signal to
+            // a1 = signal
+            let fd = frame.general_registers[Registers::A0.idx()];
+            let signal = frame.general_registers[Registers::A1.idx()];
+            let result = send_signal_to_handle(frame.pid, fd, signal);
+            set_return_value(frame, result.map(|_| 0).map_err(|e| e.as_register()));
+        }
+        SetSignalHandler => {
+            let handler = frame.general_registers[Registers::A0.idx()];
+            try_get_process(&frame.pid).write().signal_handler =
+                if handler == 0 { None } else { Some(handler) };
+        }
+        SignalReturn => {
+            if let Err(e) = crate::signal::signal_return(frame) {
+                set_return_value(frame, Err(e.as_register()));
+            }
+        }
+        SetPriority => {
+            let fd = frame.general_registers[Registers::A0.idx()];
+            let nice = frame.general_registers[Registers::A1.idx()] as isize;
+            let result = set_priority(frame.pid, fd, nice);
+            set_return_value(
+                frame,
+                result.map(|old| old as usize).map_err(|e| e.as_register()),
+            );
+        }
+        SetAffinity => {
+            let fd = frame.general_registers[Registers::A0.idx()];
+            let allowed_harts = frame.general_registers[Registers::A1.idx()];
+            let result = set_affinity(frame.pid, fd, allowed_harts);
+            set_return_value(frame, result.map_err(|e| e.as_register()));
+        }
+        SetHartOnline => {
+            let hart_id = frame.general_registers[Registers::A0.idx()];
+            let online = frame.general_registers[Registers::A1.idx()] != 0;
+            let result = set_hart_online(frame.pid, hart_id, online);
+            set_return_value(frame, result.map(|()| 0).map_err(|e| e.as_register()));
+        }
+        Sleep => {
+            let current_pid = frame.pid;
+            let nanos = frame.general_registers[Registers::A0.idx()] as u64;
+            let fut = async move {
+                timeout::TimeoutFuture::after(Duration::from_nanos(nanos)).await;
+                set_return_value(frame, Ok(0));
+            };
+            block_and_return_to_userspace(current_pid, alloc::boxed::Box::pin(fut));
+        }
+        ClockGet => {
+            let clock = frame.general_registers[Registers::A0.idx()];
+            let result = match time::ClockId::try_from(clock) {
+                Ok(time::ClockId::Monotonic) => {
+                    Ok(crate::time::Instant::now().since_boot().as_nanos() as usize)
+                }
+                Ok(time::ClockId::Realtime) => crate::time::realtime()
+                    .map(|now| now.as_nanos() as usize)
+                    .ok_or_else(|| time::ClockError::Unavailable.as_register()),
+                Err(_) => Err(time::ClockError::InvalidClock.as_register()),
+            };
+            set_return_value(frame, result);
         }
 
         Unknown => {
@@ -228,8 +262,237 @@ pub fn do_syscall(frame: *mut TrapFrame) {
     unsafe { write_satp(frame.kernel_satp) };
 }
 
-pub fn syscall_exit(frame: &mut TrapFrame, _return_code: usize) {
-    crate::process::delete_process(frame.pid);
+/// Writes the result of a handle operation into the registers that userspace reads it from
+pub fn set_return_value(frame: &mut TrapFrame, result: Result<usize, EncodedError>) {
+    match result {
+        Ok(o) => {
+            frame.general_registers[Registers::A0.idx()] = o;
+            frame.general_registers[Registers::A1.idx()] = 0;
+        }
+        Err(e) => {
+            frame.general_registers[Registers::A0.idx()] = usize::MAX;
+            frame.general_registers[Registers::A1.idx()] = e.0;
+            // Copy the other parameters
+            frame.general_registers[Registers::A2.idx()
+                ..(Registers::A2.idx() + e.1.len()).min(Registers::A7.idx())]
+                .copy_from_slice(&e.1);
+        }
+    }
+}
+
+async fn duplicate_handle(
+    pid: usize,
+    fd: usize,
+    destination: usize,
+    slot: usize,
+) -> Result<usize, EncodedError> {
+    let process = try_get_process(&pid);
+    if destination == usize::MAX {
+        let mut process = process.write();
+        let slot = if slot == usize::MAX {
+            crate::handle::next_fd_number(&process.handles)
+        } else if process.handles.contains_key(&slot) {
+            return Err(DuplicateError::SlotTaken.as_register());
+        } else {
+            slot
+        };
+        let handle = process
+            .handles
+            .get(&fd)
+            .ok_or(DuplicateError::NoSuchHandle.as_register())?
+            .duplicate(slot);
+        process.handles.insert(slot, handle);
+        Ok(slot)
+    } else {
+        let (handle, destination_backend, destination_id) = {
+            let process = process.read();
+            let destination = process
+                .handles
+                .get(&destination)
+                .ok_or(DuplicateError::NoSuchHandle.as_register())?;
+            let handle = process
+                .handles
+                .get(&fd)
+                .ok_or(DuplicateError::NoSuchHandle.as_register())?
+                .duplicate(slot);
+            (
+                handle,
+                destination.backend.upgrade(),
+                destination.backend_meta,
+            )
+        };
+        destination_backend
+            .unwrap()
+            .receive_handle(&destination_id, slot, handle)
+            .await
+    }
+}
+
+fn send_signal_to_handle(pid: usize, fd: usize, signal: usize) -> Result<(), signal::SignalError> {
+    let signal =
+        signal::Signal::try_from(signal).map_err(|_| signal::SignalError::InvalidSignal)?;
+    let target = process_of_handle(pid, fd).ok_or(signal::SignalError::NoSuchProcess)?;
+    crate::signal::send_signal(pid, target, signal)
+}
+
+/// The process that a handle refers to, if the backend knows about one
+fn process_of_handle(pid: usize, fd: usize) -> Option<usize> {
+    let process = try_get_process(&pid);
+    let process = process.read();
+    let handle = process.handles.get(&fd)?;
+    handle
+        .backend
+        .upgrade()
+        .and_then(|backend| backend.process_id(&handle.backend_meta))
+}
+
+fn set_priority(pid: usize, fd: usize, nice: isize) -> Result<isize, PriorityError> {
+    if !(MIN_NICE..=MAX_NICE).contains(&nice) {
+        return Err(PriorityError::InvalidPriority);
+    }
+    let target = if fd == usize::MAX {
+        pid
+    } else {
+        process_of_handle(pid, fd).ok_or(PriorityError::NoSuchProcess)?
+    };
+    let user_id = try_get_process(&pid).read().user_id;
+    let target = process::weak_get_process(&target)
+        .upgrade()
+        .ok_or(PriorityError::NoSuchProcess)?;
+    let mut target = target.write();
+    if user_id != 0 && (user_id != target.user_id || nice < target.nice) {
+        return Err(PriorityError::NoPermission);
+    }
+    Ok(core::mem::replace(&mut target.nice, nice))
+}
+
+/// Maps pages into the process's address space and returns the virtual address they start at
+fn alloc_pages(frame: &mut TrapFrame) -> Result<usize, AllocPagesError> {
+    let virtual_address = frame.general_registers[Registers::A0.idx()];
+    let physical_addr = frame.general_registers[Registers::A1.idx()];
+    let size = frame.general_registers[Registers::A2.idx()];
+    let mut flags = frame.general_registers[Registers::A3.idx()];
+    flags = (flags & !EntryBits::ADDRESS_MASK) | EntryBits::USER;
+
+    // TODO fix aliasing issues!
+    let mut root_table = unsafe { frame.satp_as_sv39_root_table() };
+
+    let physical_addr = if physical_addr == usize::MAX {
+        None
+    } else {
+        if try_get_process(&mut frame.pid).read().user_id == 0 {
+            Some(physical_addr)
+        } else {
+            unimplemented!(
+                "Mapping virtual address space to physical address space provided by user"
+            )
+        }
+    };
+
+    let virtual_address = if virtual_address == usize::MAX {
+        // Find a free set of contiguous pages
+        let mut run_length = 0;
+        let mut first_page_in_set = None;
+        for i in (0x1000..0x80000000).step_by(4096) {
+            if let Ok(page) = unsafe { root_table.query(i) } {
+                // This page is used
+                if page.0.value & EntryBits::USER != 0 {
+                    run_length = 0;
+                    continue;
+                }
+            }
+
+            // This page is free and unmapped
+            if run_length >= size {
+                first_page_in_set = Some(i - run_length);
+                break;
+            }
+            run_length += 4096;
+        }
+        first_page_in_set.expect("No free page found!")
+    } else if is_user_range(virtual_address, size) {
+        virtual_address
+    } else {
+        return Err(AllocPagesError::InvalidRange);
+    };
+
+    let size = size.div_ceil(4096) * 4096;
+    let paging_flags = flags & EntryBits::RWX;
+
+    match physical_addr {
+        Some(physical_addr) => {
+            // If this is memory from the frame allocator, it's now shared with this process
+            for offset in (0..size).step_by(4096) {
+                crate::frame_allocator::retain(physical_addr + offset);
+            }
+            root_table.map(
+                physical_addr,
+                virtual_address,
+                size,
+                paging_flags | EntryBits::VALID | EntryBits::USER,
+            );
+        }
+        None => {
+            // Each page gets its own frame, so that they can be freed one by one
+            let mut page_frames = alloc::vec::Vec::new();
+            for offset in (0..size).step_by(4096) {
+                let Some(page_frame) = crate::frame_allocator::allocate_zeroed(0) else {
+                    // Undo the pages mapped until now
+                    root_table.unmap(virtual_address, offset);
+                    core::mem::forget(root_table);
+                    for page_frame in page_frames {
+                        crate::frame_allocator::release(page_frame);
+                    }
+                    return Err(AllocPagesError::OutOfMemory);
+                };
+                page_frames.push(page_frame);
+                root_table.map(
+                    page_frame,
+                    virtual_address + offset,
+                    4096,
+                    paging_flags | EntryBits::VALID | EntryBits::USER,
+                );
+            }
+        }
+    }
+    core::mem::forget(root_table);
+    Ok(virtual_address)
+}
+
+fn set_affinity(pid: usize, fd: usize, allowed_harts: usize) -> Result<usize, AffinityError> {
+    if !crate::scheduler::allows_any_hart(allowed_harts) {
+        return Err(AffinityError::NoHarts);
+    }
+    let target = if fd == usize::MAX {
+        pid
+    } else {
+        process_of_handle(pid, fd).ok_or(AffinityError::NoSuchProcess)?
+    };
+    let user_id = try_get_process(&pid).read().user_id;
+    let target = process::weak_get_process(&target)
+        .upgrade()
+        .ok_or(AffinityError::NoSuchProcess)?;
+    let mut target = target.write();
+    if user_id != 0 && user_id != target.user_id {
+        return Err(AffinityError::NoPermission);
+    }
+    // If the process is running on a hart that isn't allowed anymore, it moves when its slice ends
+    Ok(core::mem::replace(&mut target.allowed_harts, allowed_harts))
+}
+
+fn set_hart_online(pid: usize, hart_id: usize, online: bool) -> Result<(), HartError> {
+    {
+        let process = try_get_process(&pid);
+        let process = process.read();
+        if process.user_id != 0 && !process.is_supervisor {
+            return Err(HartError::NoPermission);
+        }
+    }
+    crate::hart::set_online(hart_id, online)
+}
+
+pub fn syscall_exit(frame: &mut TrapFrame, return_code: usize) {
+    crate::process::exit_process(frame.pid, return_code);
     context_switch::schedule_and_switch();
 }
 
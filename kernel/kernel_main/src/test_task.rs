@@ -3,20 +3,18 @@
 
 use alloc::{collections::BTreeSet, vec::Vec};
 use core::{
-    arch::asm,
     ops::{BitAnd, BitXor},
     pin::Pin,
     task::Context,
 };
 
 use kernel_io::Write;
-use kernel_util::boxed_slice_with_alignment;
 use to_trait::ToTraitExt;
 
 use crate::{
     arc_inject::WeakInjectRwLock,
     asm::do_supervisor_syscall_0,
-    cpu::{self, Registers},
+    cpu::Registers,
     drivers::{
         traits::block::GenericBlockDevice,
         virtio::gpu::{init_dev, VirtioGpuDriverE},
@@ -24,11 +22,9 @@ use crate::{
     external_interrupt::ExternalInterruptHandler,
     fdt,
     filesystem::ext2::Ext2,
-    paging::{
-        EntryBits::{self, RWX, VALID},
-        Paging,
-    },
-    process, timeout, virtual_buffers,
+    frame_allocator,
+    paging::{address_space::AddressSpace, EntryBits, Paging},
+    process,
 };
 
 // random-ish function I just made up
@@ -98,10 +94,7 @@ pub fn test_task_2() {
     drop(vector_vec);
 
     use crate::timeout::TimeoutFuture;
-    // On QEMU, 10_000_000 timebase is 1 second
-    let mut future = TimeoutFuture {
-        for_time: timeout::get_time() + 10_000_000,
-    };
+    let mut future = TimeoutFuture::after(core::time::Duration::from_secs(1));
     let waker = process::Process::this().write().construct_waker();
     use core::future::Future;
 
@@ -212,86 +205,82 @@ pub fn test_task_3() {
         let mut handle = ext2.inode_handle(inode).await.unwrap();
         use kernel_io::Read;
         let t = handle.read_to_end_new().await.unwrap();
-        let mut new_page_table = Box::new(crate::paging::Table::zeroed());
+        let mut address_space = AddressSpace::new();
 
         info!("Read /main program");
 
-        let mut root_table = crate::paging::sv39::RootTable(&mut new_page_table);
-
-        virtual_buffers::initialize_root_table(&mut root_table);
-
-        //root_table.identity_map();
-        root_table.map(0x80000000, 0x80000000, 0x80000000, VALID | RWX);
+        let mut root_table = address_space.root_table();
 
         let elf_file = elf_rs::Elf::from_bytes(&t.1);
-        let mut allocated_segments = Vec::new();
         if let elf_rs::Elf::Elf64(e) = elf_file.unwrap() {
             for p in e.program_header_iter() {
                 if p.ph.memsz() as usize == 0 {
                     continue;
                 }
                 use core::convert::TryInto;
-                let page_offset: usize = (p.ph.vaddr() - p.ph.vaddr().div_floor(4096) * 4096)
-                    .try_into()
-                    .unwrap();
-                // This is our buffer with the program's data for this segment
-                let mut segment =
-                    boxed_slice_with_alignment(p.ph.memsz() as usize + page_offset, 4096, &0u8);
-
-                // Copy segment to buffer
-                segment[page_offset..page_offset + p.segment().len()].copy_from_slice(p.segment());
-                let start = p.ph.vaddr();
-                let end = start + p.ph.memsz();
-                let start = start.div_floor(4096) * 4096;
-                let end = end.div_ceil(4096) * 4096;
-
-                // Map buffer to process's address space
-                root_table.map(
-                    &segment[0] as *const u8 as usize,
-                    start.try_into().unwrap(),
-                    (end - start).try_into().unwrap(),
-                    if p.ph.flags() & 1 != 0 {
-                        EntryBits::EXECUTE
-                    } else {
-                        0
-                    } | if p.ph.flags() & 2 != 0 {
-                        EntryBits::WRITE
-                    } else {
-                        0
-                    } | if p.ph.flags() & 4 != 0 {
-                        EntryBits::READ
-                    } else {
-                        0
-                    } | EntryBits::VALID
-                        | EntryBits::USER,
-                );
-                allocated_segments.push(segment);
+                let vaddr: usize = p.ph.vaddr().try_into().unwrap();
+                let data = p.segment();
+                let start = vaddr.div_floor(4096) * 4096;
+                let end = (vaddr + p.ph.memsz() as usize).div_ceil(4096) * 4096;
+                let flags = if p.ph.flags() & 1 != 0 {
+                    EntryBits::EXECUTE
+                } else {
+                    0
+                } | if p.ph.flags() & 2 != 0 {
+                    EntryBits::WRITE
+                } else {
+                    0
+                } | if p.ph.flags() & 4 != 0 {
+                    EntryBits::READ
+                } else {
+                    0
+                } | EntryBits::VALID
+                    | EntryBits::USER;
+
+                // One frame per page, since frames are freed one page at a time
+                for page in (start..end).step_by(4096) {
+                    let frame =
+                        frame_allocator::allocate_zeroed(0).expect("Out of memory for the program");
+                    // The part of the segment's data that's in this page
+                    let copy_start = page.max(vaddr);
+                    let copy_end = (page + 4096).min(vaddr + data.len());
+                    if copy_start < copy_end {
+                        let frame_slice =
+                            unsafe { core::slice::from_raw_parts_mut(frame as *mut u8, 4096) };
+                        frame_slice[copy_start - page..copy_end - page]
+                            .copy_from_slice(&data[copy_start - vaddr..copy_end - vaddr]);
+                    }
+                    root_table.map(frame, page, 4096, flags);
+                }
+            }
+
+            let mut handles = alloc::collections::BTreeMap::new();
+            if let Err(e) = crate::handle_backends::open_standard_handles(&mut handles).await {
+                error!("Couldn't open the standard handles for /main: {:?}", e);
+                return;
             }
 
+            // Create a page with the program's stack
+            let program_stack =
+                frame_allocator::allocate_zeroed(0).expect("Out of memory for the stack");
+            root_table.map(
+                program_stack,
+                0x40000,
+                4096,
+                EntryBits::VALID | EntryBits::READ | EntryBits::WRITE | EntryBits::USER,
+            );
+
             process::new_process(|process| {
-                // Create a buffer with the program's stack
-                let program_stack = boxed_slice_with_alignment(4096, 4096, &0u8);
-                root_table.map(
-                    &program_stack[0] as *const _ as usize,
-                    0x40000,
-                    4096,
-                    EntryBits::VALID | EntryBits::READ | EntryBits::WRITE | EntryBits::USER,
-                );
+                process.handles = handles;
                 process.trap_frame.general_registers[Registers::Sp.idx()] = 0x40800;
                 process.trap_frame.pc = e.header().entry_point() as usize;
-                core::mem::forget(program_stack);
                 process.is_supervisor = false;
                 process.name = Some(alloc::string::String::from("/main"));
-                process.trap_frame.satp = (&*root_table.0 as *const crate::paging::Table as usize)
-                    >> 12
-                    | cpu::csr::SATP_SV39;
+                process.address_space = Some(address_space);
 
                 info!("Created process for /main program");
             });
         };
-        core::mem::forget(allocated_segments);
-        core::mem::forget(root_table);
-        core::mem::forget(new_page_table);
 
         //crate::sbi::shutdown(0);
     };
@@ -311,14 +300,5 @@ pub fn test_task_3() {
 
 #[inline]
 fn trigger_yield_syscall() {
-    unsafe {
-        asm!(r"
-			li a7, 2
-			# Trigger a timer interrupt
-			csrr t0, sip
-			# Set SSIP
-			ori t0, t0, 2
-			csrw sip, t0
-		", out("a7") _, out("t0") _)
-    }
+    unsafe { do_supervisor_syscall_0(2) };
 }
@@ -0,0 +1,130 @@
+//! Points in time and conversions between `Duration`s and timer ticks
+//!
+//! The time CSR counts ticks at the frequency in the `/cpus/timebase-frequency` property of the device tree,
+//! which is read at boot by `init`.
+//!
+//! The realtime clock (time since the Unix epoch) is only available once an RTC has been found,
+//! and it's kept as an offset from the monotonic clock so that reading it doesn't need the RTC.
+
+use core::{
+    ops::{Add, AddAssign, Sub},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    fdt::{self, PropertyValue},
+    timeout::get_time,
+};
+
+/// Used until the device tree has been read. This is the frequency on QEMU's virt machine
+const DEFAULT_TIMEBASE_FREQUENCY: u64 = 10_000_000;
+
+static TIMEBASE_FREQUENCY: AtomicU64 = AtomicU64::new(DEFAULT_TIMEBASE_FREQUENCY);
+
+/// Reads the timebase frequency from the device tree. Needs the FDT to be initialized
+pub fn init() {
+    let frequency = fdt::root()
+        .read()
+        .get("cpus")
+        .and_then(|cpus| match cpus.properties.get("timebase-frequency") {
+            Some(PropertyValue::u32(frequency)) => Some(*frequency as u64),
+            Some(PropertyValue::u64(frequency)) => Some(*frequency),
+            _ => None,
+        })
+        .filter(|frequency| *frequency != 0);
+    match frequency {
+        Some(frequency) => TIMEBASE_FREQUENCY.store(frequency, Ordering::Relaxed),
+        None => warn!(
+            "No timebase-frequency in the device tree, assuming {} Hz",
+            DEFAULT_TIMEBASE_FREQUENCY
+        ),
+    }
+    info!(
+        "Timebase frequency: {} Hz",
+        TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+    );
+}
+
+/// Ticks per second of the time CSR
+pub fn timebase_frequency() -> u64 {
+    TIMEBASE_FREQUENCY.load(Ordering::Relaxed)
+}
+
+pub fn ticks_from_duration(duration: Duration) -> u64 {
+    (duration.as_nanos() * timebase_frequency() as u128 / 1_000_000_000) as u64
+}
+
+pub fn duration_from_ticks(ticks: u64) -> Duration {
+    Duration::from_nanos((ticks as u128 * 1_000_000_000 / timebase_frequency() as u128) as u64)
+}
+
+/// Nanoseconds since the Unix epoch at boot, or 0 if there's no RTC
+static REALTIME_AT_BOOT: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the realtime clock to `now` (time since the Unix epoch). Called by RTC drivers
+pub fn set_realtime(now: Duration) {
+    let at_boot = now.saturating_sub(Instant::now().since_boot());
+    REALTIME_AT_BOOT.store(at_boot.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Time since the Unix epoch, or None if there's no RTC
+pub fn realtime() -> Option<Duration> {
+    match REALTIME_AT_BOOT.load(Ordering::Relaxed) {
+        0 => None,
+        at_boot => Some(Duration::from_nanos(at_boot) + Instant::now().since_boot()),
+    }
+}
+
+/// A point in time, measured in ticks since boot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Self(get_time())
+    }
+
+    pub fn from_ticks(ticks: u64) -> Self {
+        Self(ticks)
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.0
+    }
+
+    pub fn since_boot(&self) -> Duration {
+        duration_from_ticks(self.0)
+    }
+
+    /// Zero if `earlier` is later than this
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        duration_from_ticks(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Self::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + ticks_from_duration(duration))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, duration: Duration) {
+        *self = *self + duration;
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
@@ -1,20 +1,22 @@
 /// This module uses time interrupts to create a "timeout" future
-use alloc::vec::Vec;
+use alloc::collections::BTreeMap;
 use core::{
     future::Future,
     task::{Poll, Waker},
+    time::Duration,
 };
 
 use cpu::{MMIO_MTIME, read_time};
 
 use crate::{
-    cpu, lock::shared::RwLock, paging::PAGE_ALIGN, timer_queue, virtual_buffers::new_virtual_buffer,
+    cpu,
+    lock::shared::{Mutex, RwLock},
+    paging::PAGE_ALIGN,
+    time::Instant,
+    timer_queue,
+    virtual_buffers::new_virtual_buffer,
 };
 
-/// This Vec is sorted by item.0.for_time
-/// TODO use binary heap?
-pub static WAITING_TIMEOUTS: RwLock<Vec<(TimeoutFuture, Waker)>> = RwLock::new(Vec::new());
-
 pub static MMIO_MTIME_VIRT_BUFFER_ADDR: RwLock<Option<usize>> = RwLock::new(None);
 
 // Uses a virt buffer, so it
@@ -37,86 +39,128 @@ pub fn get_time() -> u64 {
     }
 }
 
+/// Timeouts waiting to be woken, ordered by time. The second part of the key tells apart
+/// timeouts for the same time, so that they all get woken
+struct WaitingTimeouts {
+    wakers: BTreeMap<(u64, u64), Waker>,
+    next_id: u64,
+    // The earliest time a TimeoutFuture timer event has been scheduled for, if any
+    scheduled: Option<u64>,
+}
+
+static WAITING_TIMEOUTS: Mutex<WaitingTimeouts> = Mutex::new(WaitingTimeouts {
+    wakers: BTreeMap::new(),
+    next_id: 0,
+    scheduled: None,
+});
+
+impl WaitingTimeouts {
+    /// Makes sure that there's a timer event for the earliest timeout
+    fn schedule_earliest(&mut self) {
+        let earliest = match self.wakers.keys().next() {
+            Some((time, _)) => *time,
+            None => return,
+        };
+        if self.scheduled.map(|s| s <= earliest).unwrap_or(false) {
+            return;
+        }
+        self.scheduled = Some(earliest);
+        use crate::timer_queue::{TimerEvent, TimerEventCause};
+        timer_queue::schedule_at(TimerEvent {
+            instant: earliest,
+            cause: TimerEventCause::TimeoutFuture,
+        });
+        timer_queue::schedule_next();
+    }
+}
+
+/// Completes at a point in time. Dropping it before it completes cancels it
 #[must_use = "Futures do nothing unless polled"]
-#[derive(Clone)]
 pub struct TimeoutFuture {
-    pub for_time: u64,
+    for_time: u64,
+    // Key in WAITING_TIMEOUTS while it's waiting
+    registration: Option<(u64, u64)>,
 }
 
 impl TimeoutFuture {
     pub fn absolute(for_time: u64) -> Self {
-        Self { for_time }
+        Self {
+            for_time,
+            registration: None,
+        }
     }
     pub fn relative(for_time: u64) -> Self {
         Self::absolute(for_time + get_time())
     }
+    pub fn until(instant: Instant) -> Self {
+        Self::absolute(instant.ticks())
+    }
+    pub fn after(duration: Duration) -> Self {
+        Self::until(Instant::now() + duration)
+    }
+    pub fn for_time(&self) -> u64 {
+        self.for_time
+    }
 }
 
 impl Future for TimeoutFuture {
     type Output = u64;
 
     fn poll(
-        self: core::pin::Pin<&mut Self>,
+        mut self: core::pin::Pin<&mut Self>,
         cx: &mut core::task::Context<'_>,
     ) -> Poll<Self::Output> {
-        if get_time() >= self.for_time {
-            Poll::Ready(get_time())
-        } else {
-            // Register the current task for wakeup
-            // WAITING_TIMEOUTS gets checked during a time interrupt
-
-            // binary_search_by returns Err when the item is not found
-            // This is what we expect, because there shouldn't be two equal TimeoutFuture::for_time values in the Vec
-            let index = WAITING_TIMEOUTS
-                .write()
-                .binary_search_by(|s| self.for_time.cmp(&s.0.for_time));
-
-            let insert_position;
-
-            match index {
-                Ok(_index) => {
-                    // If this waker was already present, then we don't need to schedule it again
-                    return Poll::Pending;
-                }
-                Err(index) => insert_position = index,
+        let now = get_time();
+        if now >= self.for_time {
+            if let Some(key) = self.registration.take() {
+                WAITING_TIMEOUTS.lock().wakers.remove(&key);
             }
+            return Poll::Ready(now);
+        }
 
-            // Remember to wake up this future when necessary
-            WAITING_TIMEOUTS
-                .write()
-                .insert(insert_position, (self.clone(), cx.waker().clone()));
-
-            // Trigger a timer interrupt in the target time
-            use crate::timer_queue::{TimerEvent, TimerEventCause};
-            timer_queue::schedule_at(TimerEvent {
-                instant: self.for_time,
-                cause: TimerEventCause::TimeoutFuture,
-            });
+        // Remember to wake up this future when necessary
+        // (on_timer_event gets called on a timer interrupt)
+        let mut waiting = WAITING_TIMEOUTS.lock();
+        let key = match self.registration {
+            Some(key) => key,
+            None => {
+                let key = (self.for_time, waiting.next_id);
+                waiting.next_id += 1;
+                self.registration = Some(key);
+                key
+            }
+        };
+        waiting.wakers.insert(key, cx.waker().clone());
+        waiting.schedule_earliest();
+        Poll::Pending
+    }
+}
 
-            Poll::Pending
+impl Drop for TimeoutFuture {
+    fn drop(&mut self) {
+        if let Some(key) = self.registration.take() {
+            WAITING_TIMEOUTS.lock().wakers.remove(&key);
         }
     }
-    // add code here
 }
 
 // This gets called by trap.rs on a timer interrupt scheduled by us
 pub fn on_timer_event(instant: u64) {
-    info!("Timer event for us {}", instant);
-    let mut lock = WAITING_TIMEOUTS.write();
-    let mut max_remove_index = 0;
-    for (idx, (future, waker)) in lock.iter().enumerate() {
-        if future.for_time <= instant {
-            // This future has already timed out. Wake it.
-            waker.wake_by_ref();
-            max_remove_index = idx + 1;
-        }
-    }
-    for i in 0..max_remove_index {
-        // This would trigger a unused-future warning otherwise
-        // because rustc isn't smart enough to realize that all futures before max_remove_index would have been woken up before being removed
-        #[allow(unused_must_use)]
-        {
-            lock.remove(i);
+    debug!("Timer event for us {}", instant);
+    let now = get_time().max(instant);
+    let expired = {
+        let mut waiting = WAITING_TIMEOUTS.lock();
+        if waiting.scheduled.map(|s| s <= now).unwrap_or(false) {
+            waiting.scheduled = None;
         }
+        // Everything before (now + 1, 0) has expired
+        let pending = waiting.wakers.split_off(&(now + 1, 0));
+        let expired = core::mem::replace(&mut waiting.wakers, pending);
+        waiting.schedule_earliest();
+        expired
+    };
+    // Wake them without holding the lock, since waking might poll the futures right away
+    for (_, waker) in expired {
+        waker.wake();
     }
 }
@@ -11,6 +11,8 @@ pub enum TimerEventCause {
     ContextSwitch,
     TimeoutFuture,
     SystemStatus,
+    /// An idle hart has been idle for long enough to suspend
+    IdleSuspend,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -71,14 +73,15 @@ pub fn last_cause() -> TimerEvent {
 
 pub fn schedule_next() {
     // Call SBI to schedule the next timer interrupt
+    // If there's nothing to wait for (like on idle harts), the timer is set infinitely far into the future
     let next_time = TIMER_QUEUE
         .read()
         .get(&load_hartid())
         .expect("Hartid queue not found!")
         .read()
         .peek()
-        .expect("Deadlock: Timer queue was drained to zero This should never happen!")
-        .instant;
+        .map(|event| event.instant)
+        .unwrap_or(2_u64.pow(63));
     // Note that the get_timer_queue().read() must be unlocked here
     // because the timer interrupt might trigger immeditately
 
@@ -108,3 +111,35 @@ pub fn schedule_at_or_earlier(event: TimerEvent) {
         timer_queue.push(event);
     }
 }
+
+/// Like schedule_at, but on another hart. The hart has to call schedule_next afterwards
+pub fn schedule_on(hart_id: usize, event: TimerEvent) {
+    let t = TIMER_QUEUE.read();
+    let e = t.get(&hart_id).expect("Hartid queue not found! (3)");
+    e.write().push(event);
+}
+
+/// Removes the timer queue of a hart that is going offline, and returns its events
+pub fn remove_hart(hart_id: usize) -> alloc::vec::Vec<TimerEvent> {
+    TIMER_QUEUE
+        .write()
+        .remove(&hart_id)
+        .map(|queue| queue.into_inner().into_vec())
+        .unwrap_or_default()
+}
+
+/// Removes the events with this cause
+pub fn cancel(cause: TimerEventCause) {
+    let t = TIMER_QUEUE.read();
+    let e = t.get(&load_hartid()).expect("Hartid queue not found! (2)");
+    e.write().retain(|ev| ev.cause != cause);
+}
+
+/// Removes the events with the same cause as `event`, and schedules `event` instead
+pub fn replace(event: TimerEvent) {
+    let t = TIMER_QUEUE.read();
+    let e = t.get(&load_hartid()).expect("Hartid queue not found! (2)");
+    let mut timer_queue = e.write();
+    timer_queue.retain(|ev| ev.cause != event.cause);
+    timer_queue.push(event);
+}
@@ -0,0 +1,106 @@
+//! Keeping the TLBs of all harts in sync with the page tables
+//!
+//! Harts cache translations, so after a mapping is removed or its permissions are reduced,
+//! every hart that might still have the old translation has to flush it.
+//! The current hart does that with `sfence.vma`, other harts get asked to do it through SBI RFENCE.
+
+use core::{arch::asm, sync::atomic::Ordering};
+
+use crate::{
+    asid::{self, hart_bit},
+    cpu::load_hartid,
+    hart::HART_META,
+    paging::{satp_root_table, Table, PAGE_SIZE, ROOT_PAGE},
+    sbi::{self, HartMask},
+};
+
+/// Ranges bigger than this many pages get flushed entirely instead of page by page
+const MAX_RANGED_FLUSH_PAGES: usize = 64;
+
+fn kernel_root() -> usize {
+    unsafe { &ROOT_PAGE as *const Table as usize }
+}
+
+/// Flushes the translations for the range in this hart
+/// If asid is None, translations in all address spaces are flushed
+pub fn flush_local(start: usize, size: usize, asid: Option<usize>) {
+    let asid = asid.unwrap_or(0);
+    let has_asid = asid != 0;
+    if size.div_ceil(PAGE_SIZE) > MAX_RANGED_FLUSH_PAGES {
+        if has_asid {
+            unsafe { asm!("sfence.vma zero, {0}", in(reg) asid) };
+        } else {
+            unsafe { asm!("sfence.vma") };
+        }
+    } else {
+        let start = start - start % PAGE_SIZE;
+        for address in (start..start + size.max(1)).step_by(PAGE_SIZE) {
+            if has_asid {
+                unsafe { asm!("sfence.vma {0}, {1}", in(reg) address, in(reg) asid) };
+            } else {
+                unsafe { asm!("sfence.vma {0}, zero", in(reg) address) };
+            }
+        }
+    }
+}
+
+/// Flushes the translations for the range in the harts in `harts` (bit N = hart ID N), except this one
+/// and the ones that are offline
+pub fn flush_remote(harts: usize, start: usize, size: usize, asid: Option<usize>) {
+    let this_hart = load_hartid();
+    let mut mask = 0;
+    for hart_id in HART_META.read().keys() {
+        if *hart_id == this_hart || harts & hart_bit(*hart_id) == 0 {
+            continue;
+        }
+        if *hart_id >= usize::BITS as usize {
+            // Doesn't fit in the mask
+            mask = usize::MAX;
+            break;
+        }
+        mask |= 1 << hart_id;
+    }
+    if mask == 0 {
+        return;
+    }
+    let harts = if mask == usize::MAX {
+        HartMask::all()
+    } else {
+        HartMask::from_mask(mask)
+    };
+    let result = match asid {
+        Some(asid) if asid != 0 => sbi::remote_sfence_vma_asid(harts, start, size, asid),
+        _ => sbi::remote_sfence_vma(harts, start, size),
+    };
+    if let Err(e) = result {
+        error!("TLB shootdown failed: {:?}", e);
+    }
+}
+
+/// The harts that are running with the page table at `root`
+fn harts_running(root: usize) -> usize {
+    // Every hart runs with the kernel table while handling traps
+    if root == kernel_root() {
+        return usize::MAX;
+    }
+    HART_META
+        .read()
+        .iter()
+        .filter(|(_, meta)| satp_root_table(meta.active_satp.load(Ordering::Acquire)) == root)
+        .fold(0, |mask, (hart_id, _)| mask | hart_bit(*hart_id))
+}
+
+/// Makes sure no hart keeps stale translations for the range after the page table at `root`
+/// has had mappings removed or downgraded
+pub fn shootdown(root: *const Table, start: usize, size: usize) {
+    unsafe { asm!("fence rw, rw") };
+    let root = root as usize;
+
+    // Address spaces with an ASID know which harts ran them, even the ones that switched away since
+    let (asid, harts) = match asid::lookup(root) {
+        Some((asid, harts)) if asid != 0 => (Some(asid), harts),
+        _ => (None, harts_running(root)),
+    };
+    flush_local(start, size, asid);
+    flush_remote(harts, start, size, asid);
+}
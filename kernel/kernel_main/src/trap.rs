@@ -7,10 +7,10 @@ pub use crate::trap_frame::TrapFrameExt;
 use crate::{
     context_switch,
     cpu::{self, load_hartid, read_sscratch, read_sstatus},
-    external_interrupt,
+    cpu_time, external_interrupt,
     hart::get_this_hart_meta,
-    interrupt_context_waker,
-    process::{delete_process, try_get_process},
+    interrupt_context_waker, kernel_stack,
+    process::{exit_process, try_get_process},
     sbi,
     scheduler::schedule_next_slice,
     status_summary, syscall, timeout,
@@ -47,6 +47,15 @@ impl Drop for PanicGuard {
     fn drop(&mut self) {}
 }
 
+/// What stval holds for an exception
+fn stval_meaning(cause: usize) -> &'static str {
+    match cause {
+        0 | 1 | 4 | 5 | 6 | 7 | 12 | 13 | 15 => "address",
+        2 => "instruction",
+        _ => "stval",
+    }
+}
+
 /// # Safety
 /// This should never really be called directly from Rust. There's just too many invariants that need to be satisfied
 #[no_mangle]
@@ -55,7 +64,7 @@ pub unsafe extern "C" fn trap_handler(
     tval: usize,
     cause: usize,
     _hartid: usize,
-    _sstatus: usize,
+    sstatus: usize,
     frame: *mut TrapFrame,
 ) -> usize {
     if HART_PANIC_COUNT.load(core::sync::atomic::Ordering::Acquire) != 0 {
@@ -80,6 +89,8 @@ pub unsafe extern "C" fn trap_handler(
     read_sscratch().as_mut().unwrap().set_trapped_before();
 
     set_interrupt_context();
+    // The time until now was spent running the process, from now on it's spent handling the trap
+    cpu_time::switch_activity(cpu_time::activity_for((*frame).pid, true));
     debug!("Trap from PID {:x}", unsafe { (*frame).pid });
     debug!("\x1b[1;35mV ENTER TRAP\x1b[0m");
     interrupt_context_waker::wake_all();
@@ -89,12 +100,37 @@ pub unsafe extern "C" fn trap_handler(
 
             // Supervisor software interrupt
             1 => {
-                // We use this as an smode-to-smode system call
+                // We use this as an smode-to-smode system call, and other harts send it as an IPI
                 // First, clear the SSIP bit
                 unsafe { cpu::write_sip(cpu::read_sip() & !2) };
 
-                debug!("\x1b[1;36m^ SYSCALL TRAP\x1b[0m");
-                syscall::do_syscall(frame);
+                // Any IPI sent until now is handled by this trap, or by the one raised below
+                use core::sync::atomic::Ordering;
+                let wakeup_pending = get_this_hart_meta()
+                    .unwrap()
+                    .wakeup_pending
+                    .swap(false, Ordering::AcqRel);
+
+                if (*frame).take_syscall_requested() {
+                    debug!("\x1b[1;36m^ SYSCALL TRAP\x1b[0m");
+                    if wakeup_pending {
+                        // The syscall might not return here, so the IPI gets a trap of its own
+                        unsafe { cpu::write_sip(cpu::read_sip() | 2) };
+                    }
+                    syscall::do_syscall(frame);
+                } else {
+                    // An IPI. If this hart is idle, the idle process exits when the trap returns
+                    // (it doesn't wfi if it hasn't yet) and the scheduler picks up the new work
+                    debug!("Wakeup IPI");
+                    let meta = get_this_hart_meta().unwrap();
+                    meta.is_idle.store(false, Ordering::SeqCst);
+                    if meta.stop_requested.load(Ordering::Acquire) {
+                        drop(meta);
+                        crate::hart::stop_this_hart();
+                    }
+                    // Another hart might have given us timer events when it went offline
+                    timer_queue::schedule_next();
+                }
             }
             // Supervisor timer interrupt
             5 => {
@@ -110,6 +146,10 @@ pub unsafe extern "C" fn trap_handler(
 
                         timer_queue::schedule_next();
                     }
+                    IdleSuspend => {
+                        // The idle process suspends the next time it waits, see hart::idle_wait
+                        timer_queue::schedule_next();
+                    }
                     ContextSwitch => {
                         schedule_next_slice(1);
 
@@ -176,27 +216,63 @@ pub unsafe extern "C" fn trap_handler(
                 syscall::do_syscall(frame);
             }
             _ => {
-                error!(
-                    "Error with cause: {:?} pc: {:X} *pc: {} tval: {:X} mode: {}",
-                    cause,
-                    unsafe { (*frame).pc },
-                    if (*frame).pc > 0x80000000 {
-                        alloc::format!("{:X}", unsafe { *((*frame).pc as *const u32) })
-                    } else {
-                        alloc::string::String::from("(reading might cause page fault)")
-                    },
-                    unsafe { tval as *const u32 as usize },
-                    if unsafe { read_sstatus() & 1 << 8 != 0 } {
-                        "supervisor"
-                    } else {
-                        "user"
+                let description = cpu::csr::XCAUSE_DESCRIPTION
+                    .get(cause)
+                    .copied()
+                    .unwrap_or("Unknown exception");
+                let pid = (*frame).pid;
+                // SPP is the mode the trap came from
+                let from_user = sstatus & 1 << 8 == 0;
+
+                // Page faults in a guard page mean that a kernel stack ran out
+                let overflowed_stack = match cause {
+                    12 | 13 | 15 => kernel_stack::guard_page_owner(tval),
+                    _ => None,
+                };
+                match overflowed_stack {
+                    Some(kernel_stack::StackOwner::Process(pid)) => {
+                        let name = try_get_process(&pid).read().name.clone();
+                        error!(
+                            "Stack overflow in process #{} ({})",
+                            pid,
+                            name.as_deref().unwrap_or("unnamed")
+                        );
+                    }
+                    Some(kernel_stack::StackOwner::Hart(hart_id)) => {
+                        error!("Stack overflow in the interrupt stack of hart {}", hart_id);
                     }
+                    None => {}
+                }
+                error!(
+                    "{} in {} mode at pc {:#x}, {} {:#x}, process #{}",
+                    description,
+                    if from_user { "user" } else { "supervisor" },
+                    epc,
+                    stval_meaning(cause),
+                    tval,
+                    pid
                 );
-                // Kill the process
-                if (*frame).pid > 1 {
-                    delete_process((*frame).pid);
+                (*frame).print();
+
+                if !from_user {
+                    // Unwinding an overflowed stack would read the guard page and fault again
+                    #[cfg(feature = "backtrace")]
+                    if overflowed_stack.is_none() {
+                        crate::kernel_debugging::backtrace::backtrace_from(&*frame);
+                    }
+                    panic!(
+                        "{} in kernel code at pc {:#x}, {} {:#x}",
+                        description,
+                        epc,
+                        stval_meaning(cause),
+                        tval
+                    );
                 }
-                loop {} //panic!("Non-interrupt trap");
+
+                // Only the process is at fault, so kill it and let the others run
+                read_sscratch().as_mut().unwrap().clear_in_fault_trap();
+                exit_process(pid, kernel_syscall_abi::ABNORMAL_TERMINATION);
+                context_switch::schedule_and_switch();
             }
         }
     }
@@ -210,6 +286,12 @@ pub unsafe extern "C" fn trap_handler(
         panic!("{:?}", "user process aren't really meant to do this");
     }
 
+    // SPP tells whether we're returning to user mode
+    cpu_time::switch_activity(cpu_time::activity_for(
+        (*frame).pid,
+        read_sstatus() & 1 << 8 != 0,
+    ));
+
     debug!("\x1b[1;36m^ EXIT TRAP {}\x1b[0m", load_hartid());
     clear_interrupt_context();
     epc
@@ -5,8 +5,16 @@ use crate::{
     paging::{sv39::RootTable, Table},
 };
 
+/// ABI names of the general registers
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
 pub trait TrapFrameExt {
     fn use_current_satp_as_kernel_satp(&mut self);
+    /// Prints the registers
     fn print(&self);
     /// You need to be the only owner of the trap frame to make it the current one
     unsafe fn make_current(&mut self);
@@ -30,11 +38,13 @@ impl TrapFrameExt for TrapFrame {
     }
 
     fn print(&self) {
-        println!("{:?}", "trap");
-        for (idx, i) in self.general_registers[1..].iter().enumerate() {
-            print!("0x{:0<8x} ", i);
-            if idx % 4 == 0 {
+        print!("{:>4} {:#018x}  ", "pc", self.pc);
+        for (idx, value) in self.general_registers.iter().enumerate().skip(1) {
+            print!("{:>4} {:#018x}", REGISTER_NAMES[idx], value);
+            if idx % 4 == 3 {
                 println!();
+            } else {
+                print!("  ");
             }
         }
     }
@@ -48,7 +58,11 @@ impl TrapFrameExt for TrapFrame {
     }
 
     unsafe fn satp_as_sv39_root_table(&mut self) -> RootTable<'static> {
-        RootTable(((self.satp << 12) as *mut Table).as_mut().unwrap())
+        RootTable(
+            (crate::paging::satp_root_table(self.satp) as *mut Table)
+                .as_mut()
+                .unwrap(),
+        )
     }
 
     #[cfg(feature = "backtrace")]
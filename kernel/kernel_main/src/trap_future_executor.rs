@@ -1,7 +1,7 @@
 use alloc::{boxed::Box, sync::Arc, task::Wake};
 use core::{
+    fmt::Debug,
     future::Future,
-    hint::unreachable_unchecked,
     pin::Pin,
     task::{Context, Poll, Waker},
 };
@@ -9,13 +9,15 @@ use core::{
 use kernel_cpu::read_sscratch;
 
 use crate::{
+    asid,
     context_switch::{context_switch, schedule_and_switch},
     interrupt_context_waker::InterruptContextWaker,
     lock::shared::Mutex,
-    process::ProcessState,
+    process::{weak_get_process, ProcessState},
 };
 
 struct ExtraState {
+    /// satp without the ASID, which might be recycled before the future is polled again
     satp: usize,
     pid: usize,
 }
@@ -23,7 +25,7 @@ struct ExtraState {
 impl ExtraState {
     fn save() -> Self {
         ExtraState {
-            satp: kernel_cpu::read_satp(),
+            satp: asid::with_asid(kernel_cpu::read_satp(), 0),
             pid: unsafe { (*read_sscratch()).pid },
         }
     }
@@ -32,12 +34,15 @@ impl ExtraState {
     }
 
     fn restore(&mut self) {
-        unsafe { kernel_cpu::write_satp(self.satp) }
+        // This also registers the hart in the address space, so TLB shootdowns reach it
+        unsafe { kernel_cpu::write_satp(asid::activate(self.satp)) }
     }
 }
 
 struct TrapFutureWaker {
-    future: Mutex<Option<Box<dyn Future<Output = !> + Send + Unpin>>>,
+    /// The process that made the syscall
+    pid: usize,
+    future: Mutex<Option<Box<dyn Future<Output = ()> + Send + Unpin>>>,
     state: Mutex<ExtraState>,
 }
 
@@ -47,37 +52,66 @@ impl Wake for TrapFutureWaker {
     }
 }
 
-impl Drop for TrapFutureWaker {
-    fn drop(&mut self) {
-        info!("Dropped future! (canceled)");
-    }
-}
-
 fn poll_trap_future_waker(waker: Arc<TrapFutureWaker>) -> ! {
-    {
+    let ready = {
         let interrupt_waker = Arc::new({
             let waker = Arc::downgrade(&waker);
-            InterruptContextWaker(Box::new(move || waker.upgrade().unwrap().wake_by_ref()))
+            // Nothing to do if the syscall was dropped along with its process
+            InterruptContextWaker(Box::new(move || {
+                if let Some(waker) = waker.upgrade() {
+                    waker.wake()
+                }
+            }))
         });
-        let raw_waker: Waker = interrupt_waker.clone().into();
+        let raw_waker: Waker = interrupt_waker.into();
         let mut ctx = Context::from_waker(&raw_waker);
 
-        waker.state.lock().restore();
-
-        // If the future never returns, we want to avoid leaking the waker
-        let mut k = waker.future.lock();
-        let waker = Arc::downgrade(&waker);
-        if let Poll::Ready(_never) = Pin::new(k.as_mut().unwrap()).poll(&mut ctx) {
-            // Safety: The ! type can not be constructed so we can't reach this
-            unsafe { unreachable_unchecked() }
+        let mut future = waker.future.lock();
+        let ready = match future.as_mut().map(|inner| {
+            // Not before, the address space is freed if the syscall was canceled
+            waker.state.lock().restore();
+            Pin::new(inner).poll(&mut ctx).is_ready()
+        }) {
+            Some(ready) => ready,
+            // The future is gone if the syscall was canceled
+            None => {
+                drop(future);
+                drop(waker);
+                schedule_and_switch()
+            }
         };
-        let waker = waker.upgrade().unwrap();
+        if ready {
+            // Dropped here and not inside its own poll
+            *future = None;
+        }
+        drop(future);
 
         waker.state.lock().save_in_place();
+        ready
+    };
+    let pid = waker.pid;
+    let state_pid = waker.state.lock().pid;
+    // Switching away never returns, so references held by this function would never be dropped.
+    // While the syscall is blocked, its process is what keeps the waker alive
+    drop(waker);
+
+    if ready {
+        // Whatever task we had to do is done, return to userspace now
+        match weak_get_process(&pid).upgrade() {
+            Some(process) => {
+                process.write().blocked_syscall = None;
+                drop(process);
+                context_switch(&pid)
+            }
+            // The process was killed meanwhile
+            None => schedule_and_switch(),
+        }
+    }
+
+    // The process might have been killed while the future was being polled
+    if let Some(process) = weak_get_process(&state_pid).upgrade() {
+        process.write().state = ProcessState::Yielded;
     }
-    crate::process::try_get_process(&waker.state.lock().pid)
-        .write()
-        .state = ProcessState::Yielded;
 
     // Otherwise, it means that the future is still Pending
     // The waker will be called eventually when it's needed
@@ -85,21 +119,29 @@ fn poll_trap_future_waker(waker: Arc<TrapFutureWaker>) -> ! {
     schedule_and_switch()
 }
 
-/// Executor for futures in a trap context.
-///
-/// This never returns and the future should return to userspace by itself when it is completed.
-//
-/// You should include all code that passes whatever return value the future has to userspace in the future itself
-fn block_until_never<F: 'static>(future: F) -> !
-where
-    F: FnOnce(Arc<TrapFutureWaker>) -> Pin<Box<dyn Future<Output = !> + Send>>,
-{
-    let waker = Arc::new(TrapFutureWaker {
-        future: Mutex::new(None),
-        state: Mutex::new(ExtraState::save()),
-    });
-    waker.future.lock().replace(Box::new(future(waker.clone())));
-    poll_trap_future_waker(waker)
+/// A syscall that is waiting for its future to complete.
+/// The future is dropped when this is, unless it's being polled
+pub struct BlockedSyscall(Arc<TrapFutureWaker>);
+
+impl BlockedSyscall {
+    /// Drops the syscall's future, so that it never returns to userspace.
+    /// Returns false if the future couldn't be dropped because it's being polled right now
+    pub fn cancel(&self) -> bool {
+        match self.0.future.try_lock() {
+            Some(mut future) => {
+                let future = future.take();
+                drop(future);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Debug for BlockedSyscall {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("BlockedSyscall").finish()
+    }
 }
 
 pub struct TrackDrop<G>(pub G);
@@ -117,34 +159,25 @@ impl<G> Drop for TrackDrop<G> {
     }
 }
 
+/// Executor for syscall futures in a trap context.
+///
+/// The future is polled until it completes, and then the process returns to userspace.
+/// The future has to pass whatever return value it has to userspace by itself
 pub fn block_and_return_to_userspace<F: 'static>(process: usize, future: F) -> !
 where
     F: Future<Output = ()> + Send + Unpin,
 {
-    let f = {
-        crate::process::try_get_process(&process).write().state = ProcessState::Yielded;
-        // Pin<Box<Fn() -> (Future<Box<Pin<Future>>)>>
-        move |trap| {
-            let a: Pin<Box<dyn Future<Output = !> + Send>> = Box::pin(async move {
-                future.await;
-                //println!("{:?}", &crate::process::try_get_process(&process).read().trap_frame.general_registers[Registers::A0.idx()..Registers::A7.idx()]);
-                // Whatever task we had to do is done, return to userspace now
-                unsafe {
-                    if Arc::strong_count(&trap) != 2 {
-                        warn!(
-                            "Possible memory leak of future (See {}:{}:{})",
-                            file!(),
-                            line!(),
-                            column!()
-                        )
-                    };
-                    Arc::decrement_strong_count(&trap as *const _);
-                    drop(trap);
-                }
-                context_switch(&process);
-            });
-            a
-        }
-    };
-    block_until_never(f)
+    let waker = Arc::new(TrapFutureWaker {
+        pid: process,
+        future: Mutex::new(Some(Box::new(future))),
+        state: Mutex::new(ExtraState::save()),
+    });
+    {
+        let process = crate::process::try_get_process(&process);
+        let mut process = process.write();
+        process.state = ProcessState::Yielded;
+        // Let the process be killed while it's blocked
+        process.blocked_syscall = Some(BlockedSyscall(waker.clone()));
+    }
+    poll_trap_future_waker(waker)
 }
@@ -0,0 +1,24 @@
+/// How the console treats input before processes can read it
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleMode {
+    // Input is echoed and can be edited, and reads return whole lines
+    Cooked = 0,
+    // Reads return bytes as soon as they arrive, without echo
+    Raw = 1,
+}
+
+/// First `write` option for the console backend. Requests other than `Output` ignore the buffer
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleRequest {
+    Output = 0,
+    // The second option is the new ConsoleMode
+    SetMode = 1,
+}
+
+#[derive(Debug, AsRegister)]
+pub enum ConsoleError {
+    InvalidMode,
+    InvalidRequest,
+}
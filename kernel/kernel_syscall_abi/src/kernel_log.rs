@@ -0,0 +1,35 @@
+use flat_bytes::Flat;
+
+/// First `open` option for the kernel log backend
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelLogOpenMode {
+    // Reads return 0 once all the records have been read
+    Snapshot = 0,
+    // Reads block until there are new records
+    Follow = 1,
+}
+
+/// Reading from a kernel log handle returns as many records as fit in the buffer.
+/// Each one is this header followed by `text_length` bytes of UTF-8 text
+#[derive(Flat, Debug)]
+pub struct KernelLogRecordHeader {
+    pub sequence: usize,
+    // In timer ticks
+    pub time: usize,
+    pub hart_id: usize,
+    // 1 = Error, 2 = Warn, 3 = Info, 4 = Debug, 5 = Trace
+    pub level: usize,
+    pub text_length: usize,
+}
+
+#[derive(Debug, AsRegister)]
+pub enum KernelLogError {
+    // Not even one record fits in the buffer
+    BufferTooSmall,
+    // Levels go from 0 (Off) to 5 (Trace)
+    InvalidLevel,
+    InvalidModule,
+    // Only root and kernel processes can change log levels
+    NoPermission,
+}
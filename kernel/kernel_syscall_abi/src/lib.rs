@@ -12,7 +12,7 @@ use num_enum::*;
 #[repr(usize)]
 #[derive(IntoPrimitive, FromPrimitive, Debug)]
 pub enum SyscallNumbers {
-    // Kills the task
+    // Kills the task. a0 is the exit code
     Exit = 1,
     // Marks this task as "yielded" until it gets woken up by a Waker
     Yield = 2,
@@ -30,6 +30,9 @@ pub enum SyscallNumbers {
     Seek,
     Truncate,
     Tell,
+    // Creates a new fd that shares the backend state of an existing fd,
+    // either in this process or in a process egg
+    Duplicate,
 
     // Future operations (for asynchronous tasks in the kernel or in other processes)
     // Creates a new future for use in other processes
@@ -45,15 +48,105 @@ pub enum SyscallNumbers {
     // Creates a future that completes when any of the given futures complete
     FutureOr,
 
+    // Signal operations
+    // Sends a signal (a1) to the process referred to by a handle (a0)
+    Signal = 0x30,
+    // Sets the function that gets called with the signal as its argument when this process receives a signal.
+    // a0 = address of the function, or 0 to use the default actions
+    SetSignalHandler,
+    // Called at the end of the signal handler to resume what the process was doing before
+    SignalReturn,
+
+    // Scheduling operations
+    // Sets the nice level (a1, as an isize) of the process referred to by a handle (a0),
+    // or of this process if a0 is usize::MAX. Returns the previous nice level
+    SetPriority = 0x38,
+    // Sets the harts that the process referred to by a handle (a0), or this process if a0 is usize::MAX,
+    // can run on. a1 = bitmask of hart IDs (bit N = hart ID N). Returns the previous mask
+    SetAffinity,
+    // Takes hart a0 offline (a1 = 0) or brings it back online (a1 = 1). Only for root.
+    // Processes on a hart that goes offline move to other harts
+    SetHartOnline,
+
+    // Time operations
+    // Blocks for a0 nanoseconds
+    Sleep = 0x40,
+    // Returns the time of the clock a0 (a ClockId) in nanoseconds
+    ClockGet,
+
     #[num_enum(default)]
     Unknown,
 }
 
+// The fds that every process starts with. Parents can choose what they refer to
+// before hatching a process egg; otherwise they refer to the console
+pub const STDIN_FD: usize = 0;
+pub const STDOUT_FD: usize = 1;
+pub const STDERR_FD: usize = 2;
+
+// Exit code of processes that didn't exit by themselves (for example, because of a fault)
+pub const ABNORMAL_TERMINATION: usize = usize::MAX;
+
 #[derive(AsRegister, Debug)]
 pub enum AllocPagesError {
     Unknown,
+    // The range wraps around the end of the address space
+    InvalidRange,
+    OutOfMemory,
+}
+
+#[derive(AsRegister, Debug)]
+pub enum DuplicateError {
+    NoSuchHandle,
+    // The requested fd number is already in use
+    SlotTaken,
+}
+
+// Nice levels. Lower levels get more CPU time.
+// Only user ID 0 can lower the nice level of a process
+pub const MIN_NICE: isize = -20;
+pub const MAX_NICE: isize = 19;
+
+#[derive(AsRegister, Debug)]
+pub enum PriorityError {
+    InvalidPriority,
+    // The handle doesn't refer to a process, or the process has already exited
+    NoSuchProcess,
+    NoPermission,
+}
+
+// The default affinity: any hart
+pub const ALL_HARTS: usize = usize::MAX;
+
+#[derive(AsRegister, Debug)]
+pub enum AffinityError {
+    // The mask doesn't contain any hart that exists
+    NoHarts,
+    // The handle doesn't refer to a process, or the process has already exited
+    NoSuchProcess,
+    NoPermission,
+}
+
+#[derive(AsRegister, Debug)]
+pub enum HartError {
+    NoSuchHart,
+    NoPermission,
+    // The boot hart handles external interrupts, so it can't go offline
+    BootHart,
+    // It's the only online hart some process is allowed to run on
+    ProcessesPinned,
+    AlreadyOnline,
+    AlreadyOffline,
+    // The SBI implementation couldn't start the hart
+    StartFailed,
 }
 
+pub mod console;
 pub mod directory_list;
 pub mod filesystem;
+pub mod kernel_log;
 pub mod process_egg;
+pub mod process_info;
+pub mod signal;
+pub mod time;
+pub mod user_server;
@@ -12,4 +12,9 @@ pub enum ProcessEggPacketHeader {
 #[derive(Debug, AsRegister)]
 pub enum ProcessEggError {
     Dummy,
+    // The process can only be waited for after hatching
+    NotHatched,
+    // Memory can only be written to the user half, outside of the kernel's stacks
+    InvalidAddress,
+    OutOfMemory,
 }
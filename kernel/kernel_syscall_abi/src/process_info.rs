@@ -0,0 +1,66 @@
+use flat_bytes::Flat;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+/// First `open` option for the process info backend: show every process
+pub const ALL_PROCESSES: usize = usize::MAX;
+/// First `open` option for the process info backend: show harts instead of processes
+pub const HARTS: usize = usize::MAX - 1;
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+pub enum ProcessInfoState {
+    Running = 0,
+    // Waiting for something (for example, a syscall) to complete
+    Yielded = 1,
+    // Ready to run
+    Pending = 2,
+    Scheduled = 3,
+}
+
+/// Reading from a process info handle returns as many processes as fit in the buffer, ordered by PID.
+/// Each one is this header, followed by `name_length` bytes of UTF-8 name,
+/// followed by `handle_count` handles
+#[derive(Flat, Debug)]
+pub struct ProcessInfoHeader {
+    pub pid: usize,
+    pub user_id: usize,
+    // 1 for kernel tasks
+    pub supervisor: usize,
+    // ProcessInfoState
+    pub state: usize,
+    // In bytes. User memory for user processes, the stack for kernel tasks
+    pub memory_usage: usize,
+    // In timer ticks
+    pub user_time: usize,
+    pub kernel_time: usize,
+    // An isize, from MIN_NICE to MAX_NICE
+    pub nice: usize,
+    // Bitmask of hart IDs
+    pub allowed_harts: usize,
+    pub name_length: usize,
+    pub handle_count: usize,
+}
+
+/// An open handle of a process. Followed by `backend_name_length` bytes of UTF-8 backend name
+#[derive(Flat, Debug)]
+pub struct HandleInfoHeader {
+    pub fd: usize,
+    pub backend_name_length: usize,
+}
+
+/// What reading from a process info handle opened with HARTS returns, one for each hart
+#[derive(Flat, Debug)]
+pub struct HartInfoHeader {
+    pub hart_id: usize,
+    // In timer ticks
+    pub idle_time: usize,
+    // The current time, to compare with idle_time
+    pub time: usize,
+}
+
+#[derive(Debug, AsRegister)]
+pub enum ProcessInfoError {
+    // Not even one process fits in the buffer
+    BufferTooSmall,
+    NoSuchProcess,
+}
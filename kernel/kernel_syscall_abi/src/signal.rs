@@ -0,0 +1,36 @@
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+pub enum Signal {
+    // Terminates the process. Can't be caught
+    Kill = 0,
+    // Asks the process to terminate. Terminates it if it has no handler
+    Terminate = 1,
+    // Like Terminate, but sent because the user asked for it (for example, with Ctrl+C)
+    Interrupt = 2,
+    // Ignored if the process has no handler
+    User1 = 3,
+    User2 = 4,
+}
+
+impl Signal {
+    /// Whether the process is terminated when it receives this signal and has no handler
+    pub fn terminates_by_default(&self) -> bool {
+        matches!(self, Signal::Kill | Signal::Terminate | Signal::Interrupt)
+    }
+}
+
+// Exit code of processes that were terminated by a signal
+pub const KILLED_BY_SIGNAL: usize = usize::MAX - 1;
+
+#[derive(Debug, AsRegister)]
+pub enum SignalError {
+    InvalidSignal,
+    // The handle doesn't refer to a process, or the process has already exited
+    NoSuchProcess,
+    // Only processes with the same user ID (or user ID 0) can send signals to a process
+    NoPermission,
+    // SignalReturn was called outside of a signal handler
+    NotInHandler,
+}
@@ -0,0 +1,29 @@
+use num_enum::{IntoPrimitive, TryFromPrimitive};
+
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+pub enum ClockId {
+    // Time since boot. Never goes back
+    Monotonic = 0,
+    // Time since the Unix epoch, from the RTC. Only available on machines with an RTC
+    Realtime = 1,
+}
+
+#[derive(Debug, AsRegister)]
+pub enum ClockError {
+    InvalidClock,
+    // The machine doesn't have a clock of this kind
+    Unavailable,
+}
+
+// The timer backend fires once, or periodically.
+// `open` options: a1 = nanoseconds until it fires for the first time, a2 = period in nanoseconds (0 to fire once).
+// Writing re-arms the timer with the same options in a3 and a4.
+// Reading blocks until it fires and returns how many times it fired since the last read,
+// or 0 if it fired once and has already been read.
+
+#[derive(Debug, AsRegister)]
+pub enum TimerError {
+    // The handle was closed while waiting for the timer
+    InvalidHandle,
+}
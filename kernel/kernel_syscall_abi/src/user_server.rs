@@ -0,0 +1,57 @@
+use flat_bytes::Flat;
+
+/// Third `open` option for the user server backend, after the name pointer and length
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserServerOpenMode {
+    // Register the calling process as the server for the name
+    Serve = 0,
+    // Connect to the process serving the name
+    Connect = 1,
+}
+
+/// What the server gets when reading from its server handle.
+/// For Write requests, the data written by the client follows the header
+#[derive(Flat, Debug)]
+#[repr(u8)]
+pub enum UserServerRequestHeader {
+    Open {
+        request_id: usize,
+        client_id: usize,
+        options: [usize; 3],
+    },
+    Read {
+        request_id: usize,
+        client_id: usize,
+        length: usize,
+    },
+    Write {
+        request_id: usize,
+        client_id: usize,
+    },
+    // Closing doesn't expect a reply
+    Close {
+        client_id: usize,
+    },
+}
+
+/// What the server writes to its server handle to complete a request.
+/// For Read requests, the data that will be returned to the client follows the header
+#[derive(Flat, Debug)]
+pub struct UserServerReplyHeader {
+    pub request_id: usize,
+    pub result: usize,
+    // Zero if the request succeeded
+    pub error: usize,
+}
+
+#[derive(Debug, AsRegister)]
+pub enum UserServerError {
+    NameTaken,
+    NoSuchServer,
+    ServerGone,
+    InvalidMessage,
+    BufferTooSmall,
+    // The server replied with this nonzero error code
+    RequestFailed(usize),
+}
@@ -71,4 +71,10 @@ impl TrapFrame {
     pub fn clear_in_fault_trap(&mut self) {
         self.flags &= !8
     }
+    /// Whether do_supervisor_syscall marked this frame, and clears the mark
+    pub fn take_syscall_requested(&mut self) -> bool {
+        let requested = self.flags & 16 != 0;
+        self.flags &= !16;
+        requested
+    }
 }
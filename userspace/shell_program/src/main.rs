@@ -8,11 +8,13 @@
 #![no_main]
 
 use alloc::{string::ToString, vec::Vec};
-use core::{arch::global_asm, panic::PanicInfo, ptr::read_volatile};
+use core::{arch::global_asm, panic::PanicInfo};
 
 use flat_bytes::Flat;
 use kernel_api::{
-    interrupt::wait_for_interrupt, memory::alloc_pages, println, Handle, UserspaceAllocator,
+    println,
+    stdio::{stdin, stdout},
+    UserspaceAllocator,
 };
 
 extern crate alloc;
@@ -26,40 +28,19 @@ global_asm!(include_str!("start.S"));
 const SONG_TIMINGS: [u8; 15] = [4, 2, 2, 2, 4, 4, 8, 4, 2, 4, 2, 2, 2, 4, 2];
 const SONG_BPM: u8 = 145;
 
-struct UartInput {
-    address: *mut u8,
-}
-
-impl UartInput {
-    fn new() -> Self {
-        Self {
-            address: alloc_pages(Some(0x1000_0000), Some(0x1000_0000), 0x1000, 7).unwrap()
-                as *mut _,
-        }
-    }
-    fn get_byte(&mut self) -> u8 {
-        wait_for_interrupt(0xa).unwrap();
-        unsafe { self.address.read_volatile() }
-    }
-    fn put_byte(&mut self, byte: u8) {
-        unsafe { self.address.write_volatile(byte) }
-    }
-}
-
 #[no_mangle]
 fn main() {
     loop {};
     GLOBAL_ALLOCATOR.initialize_min_size().unwrap();
-    let log_output = Handle::open(1, &[]).unwrap();
-    log_output.write(b"Hello from shell_program (/main)\n", &[]);
-    let mut input = UartInput::new();
+    stdout().write(b"Hello from shell_program (/main)\n", &[]);
     let mut s = alloc::vec![];
+    let mut buffer = [0u8; 64];
     loop {
-        let c = input.get_byte();
-        input.put_byte(c);
-        s.push(c as char);
-        if c as char == '\n' || c as char == '\r' {
-            let s = s.into_iter().collect::<alloc::string::String>();
+        // The console echoes and edits the line for us, and only returns once it's complete
+        let read = stdin().read(&mut buffer, &[]).unwrap();
+        s.extend_from_slice(&buffer[..read]);
+        if read == 0 || buffer[read - 1] == b'\n' {
+            let s = alloc::string::String::from_utf8_lossy(&s);
             //println!("You typed {}", s);
             break;
         }
@@ -11,7 +11,9 @@ use alloc::{string::ToString, vec::Vec};
 use core::{arch::global_asm, panic::PanicInfo};
 
 use flat_bytes::Flat;
-use kernel_api::{handle::open_file, println, process_egg::ProcessEgg, Handle, UserspaceAllocator};
+use kernel_api::{
+    handle::open_file, println, process_egg::ProcessEgg, stdio::stdout, UserspaceAllocator,
+};
 
 extern crate alloc;
 
@@ -23,7 +25,7 @@ global_asm!(include_str!("start.S"));
 fn main() {
     println!("{:?}", "a");
     GLOBAL_ALLOCATOR.initialize_min_size();
-    let mut log_output = Handle::open(1, &[]).unwrap();
+    let log_output = stdout();
     println!("{:?}", "b");
     log_output.write(b"Hello, world from Rust\n", &[]);
     println!("{:?}", "c");
@@ -60,6 +62,6 @@ fn main() {
         println!("{:?}", 2);
         egg_handle.set_start_address(e.header().entry_point() as usize)
     }
-    egg_handle.hatch();
+    egg_handle.hatch().unwrap();
     println!("{:?}", "finished");
 }